@@ -0,0 +1,222 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use socketioxide::SocketIo;
+use std::net::SocketAddr;
+use crate::auth::{self, AuthUser};
+use crate::state::AppState;
+
+/// Stored as the hash of accounts created through single sign-on, which
+/// have no local password until they set one. bcrypt never verifies it.
+pub const NO_PASSWORD: &str = "!";
+
+#[derive(Deserialize)]
+pub struct ProfileUpdate {
+    #[serde(rename = "displayName")]
+    display_name: Option<String>,
+    #[serde(rename = "avatarColor")]
+    avatar_color: Option<String>,
+    bio: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct PasswordChange {
+    // Left out by accounts that don't have a password yet
+    #[serde(rename = "currentPassword", default)]
+    current_password: String,
+    #[serde(rename = "newPassword")]
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteAccount {
+    password: String,
+}
+
+pub async fn get_profile(State(state): State<AppState>, user: AuthUser) -> impl IntoResponse {
+    match state.db.get_profile(&user.account_id).await {
+        Ok(Some(profile)) => Json(profile).into_response(),
+        Ok(None) => (StatusCode::NOT_FOUND, "Account not found").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load profile").into_response(),
+    }
+}
+
+/// Only the fields present in the body change; an empty string clears a field.
+pub async fn update_profile(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(payload): Json<ProfileUpdate>,
+) -> impl IntoResponse {
+    let mut profile = match state.db.get_profile(&user.account_id).await {
+        Ok(Some(profile)) => profile,
+        Ok(None) => return (StatusCode::NOT_FOUND, "Account not found").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load profile").into_response(),
+    };
+
+    if let Some(display_name) = payload.display_name {
+        let display_name = display_name.trim().to_string();
+        if display_name.chars().count() > 32 {
+            return (StatusCode::BAD_REQUEST, "Display name must be at most 32 characters").into_response();
+        }
+        profile.display_name = Some(display_name).filter(|n| !n.is_empty());
+    }
+    if let Some(avatar_color) = payload.avatar_color {
+        if !avatar_color.is_empty() && !is_hex_color(&avatar_color) {
+            return (StatusCode::BAD_REQUEST, "Avatar color must look like #rrggbb").into_response();
+        }
+        profile.avatar_color = Some(avatar_color.to_lowercase()).filter(|c| !c.is_empty());
+    }
+    if let Some(bio) = payload.bio {
+        if bio.chars().count() > 280 {
+            return (StatusCode::BAD_REQUEST, "Bio must be at most 280 characters").into_response();
+        }
+        profile.bio = Some(bio).filter(|b| !b.is_empty());
+    }
+
+    match state.db.save_profile(&profile).await {
+        Ok(_) => Json(profile).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update profile").into_response(),
+    }
+}
+
+/// Changes the password and ends every existing session of the account,
+/// returning a fresh token pair so the caller stays logged in. Accounts made
+/// through single sign-on set their first password without a current one,
+/// from a session that has just logged in at the provider.
+pub async fn change_password(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: AuthUser,
+    Json(payload): Json<PasswordChange>,
+) -> impl IntoResponse {
+    let ip = auth::client_ip(&headers, peer);
+    if let Err(response) = reauthenticate(&state, &user, &ip, &payload.current_password).await {
+        return response;
+    }
+    let errors = auth::validate_password(&payload.new_password, &user.username);
+    if !errors.is_empty() {
+        return auth::validation_failed(errors);
+    }
+
+    let Some(hashed) = auth::hash_password(payload.new_password).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to change password").into_response();
+    };
+    if state.db.set_password_hash(&user.account_id, &hashed).await.is_err()
+        || state.db.revoke_account_sessions(&user.account_id).await.is_err()
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to change password").into_response();
+    }
+    auth::disconnect_sockets(&io, format!("account:{}", user.account_id));
+
+    match auth::start_session(&state, &user.account_id, &user.username).await {
+        Ok(response) => Json(response).into_response(),
+        Err(status) => (status, "Failed to start session").into_response(),
+    }
+}
+
+pub async fn delete_account(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: AuthUser,
+    Json(payload): Json<DeleteAccount>,
+) -> impl IntoResponse {
+    let ip = auth::client_ip(&headers, peer);
+    if let Err(response) = check_password(&state, &user, &ip, &payload.password).await {
+        return response;
+    }
+
+    if state.db.delete_account(&user.account_id).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete account").into_response();
+    }
+    state.clear_room_owner(&user.account_id);
+    auth::disconnect_sockets(&io, format!("account:{}", user.account_id));
+
+    (StatusCode::OK, "Account deleted").into_response()
+}
+
+/// Re-checks the password of a logged-in account. Wrong guesses count against
+/// the same throttles as `login`, so a stolen token doesn't buy extra tries;
+/// they are only cleared by the next successful login.
+pub async fn check_password(state: &AppState, user: &AuthUser, ip: &str, password: &str) -> Result<(), axum::response::Response> {
+    let account_key = user.username.to_lowercase();
+    if let Some(retry_after) = state.account_throttle.check(&account_key)
+        .into_iter()
+        .chain(state.ip_throttle.check(ip))
+        .max()
+    {
+        return Err(auth::locked_out(retry_after));
+    }
+
+    let hash = match state.db.get_password_hash(&user.account_id).await {
+        Ok(Some(hash)) if hash == NO_PASSWORD => {
+            return Err((StatusCode::FORBIDDEN, "This account has no password yet, set one first").into_response());
+        }
+        Ok(Some(hash)) => hash,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Account not found").into_response()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify password").into_response()),
+    };
+    if auth::verify_password(password.to_string(), Some(hash)).await {
+        return Ok(());
+    }
+    state.account_throttle.record_failure(&account_key);
+    state.ip_throttle.record_failure(ip);
+    Err((StatusCode::UNAUTHORIZED, "Incorrect password").into_response())
+}
+
+/// Makes sure the caller knows more than the access token: the current
+/// password, or for accounts made through single sign-on that have none yet,
+/// a session that has just logged in at the provider.
+pub async fn reauthenticate(state: &AppState, user: &AuthUser, ip: &str, password: &str) -> Result<(), axum::response::Response> {
+    let has_password = match state.db.get_password_hash(&user.account_id).await {
+        Ok(Some(hash)) => hash != NO_PASSWORD,
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Account not found").into_response()),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify password").into_response()),
+    };
+    if has_password {
+        return check_password(state, user, ip, password).await;
+    }
+    // Otherwise a leaked access token alone would be enough to take the account over
+    match auth::recently_authenticated(state, user).await {
+        Ok(true) => Ok(()),
+        Ok(false) => Err((StatusCode::FORBIDDEN, "Log in through single sign-on again to continue").into_response()),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify session").into_response()),
+    }
+}
+
+fn is_hex_color(value: &str) -> bool {
+    value.len() == 7
+        && value.starts_with('#')
+        && value[1..].chars().all(|c| c.is_ascii_hexdigit())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyRing;
+
+    #[tokio::test]
+    async fn wrong_passwords_lock_the_account_out() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        let hashed = bcrypt::hash("right-password", 4).unwrap();
+        state.db.create_account("acc-1", "Bob", &hashed).await.unwrap();
+        let user = AuthUser { account_id: "acc-1".into(), username: "Bob".into(), session_id: String::new() };
+
+        for _ in 0..5 {
+            let response = check_password(&state, &user, "10.0.0.1", "wrong-password").await.unwrap_err();
+            assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+        // Even the right password is turned away, from any address
+        let response = check_password(&state, &user, "10.0.0.2", "right-password").await.unwrap_err();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        // And the lockout is the one login checks
+        assert!(state.account_throttle.check("bob").is_some());
+    }
+}
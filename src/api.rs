@@ -1,32 +1,648 @@
 use axum::{
-    extract::State,
+    extract::{Path, Query, State},
+    http::StatusCode,
     response::{IntoResponse, Json},
+    Extension,
 };
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use dashmap::mapref::entry::Entry;
+use serde_json::json;
+use socketioxide::SocketIo;
+use tracing::warn;
+use crate::auth::{self, AuthUser};
+use crate::chat;
+use crate::db;
+use crate::handlers;
 use crate::state::AppState;
-use serde::Serialize;
+use crate::types::{Room, Visibility};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
 #[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
 pub struct RoomSummary {
     id: String,
     name: String,
+    description: Option<String>,
+    category: Option<String>,
+    tags: Vec<String>,
     user_count: usize,
+    max_users: Option<usize>,
+    created_at: Option<i64>,
+    last_active_at: Option<i64>,
 }
 
-pub async fn list_rooms(State(state): State<AppState>) -> impl IntoResponse {
-    let mut rooms = Vec::new();
-    
-    // Get active rooms from memory
-    for room in state.rooms.iter() {
-        rooms.push(RoomSummary {
-            id: room.id.clone(),
-            name: room.name.clone(),
-            user_count: room.users.len(),
-        });
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RoomSort {
+    #[default]
+    Occupancy, // most people first
+    Recent,    // most recently joined first
+    Name,
+}
+
+#[derive(Deserialize)]
+pub struct RoomQuery {
+    q: Option<String>,
+    tags: Option<String>, // comma-separated; rooms need all of them
+    category: Option<String>,
+    #[serde(default)]
+    sort: RoomSort,
+    limit: Option<usize>,
+    cursor: Option<String>,
+}
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+
+// Rooms are ordered by this, ascending; ties on the sort field fall back to
+// the id so the order is total and cursors stay stable
+type SortKey = (i64, String, String);
+
+fn sort_key(room: &RoomSummary, sort: RoomSort) -> SortKey {
+    match sort {
+        RoomSort::Occupancy => (-(room.user_count as i64), String::new(), room.id.clone()),
+        RoomSort::Recent => (-room.last_active_at.or(room.created_at).unwrap_or(0), String::new(), room.id.clone()),
+        RoomSort::Name => (0, room.name.to_lowercase(), room.id.clone()),
+    }
+}
+
+fn encode_cursor(key: &SortKey) -> String {
+    URL_SAFE_NO_PAD.encode(serde_json::to_vec(key).unwrap_or_default())
+}
+
+fn decode_cursor(cursor: &str) -> Option<SortKey> {
+    serde_json::from_slice(&URL_SAFE_NO_PAD.decode(cursor).ok()?).ok()
+}
+
+/// The room directory: public rooms with their live occupancy. Every stored
+/// room is loaded into memory at startup, so the database only adds when
+/// rooms were created and last joined.
+pub async fn list_rooms(State(state): State<AppState>, Query(query): Query<RoomQuery>) -> impl IntoResponse {
+    let after = match query.cursor.as_deref().map(decode_cursor) {
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid cursor").into_response(),
+        Some(key) => key,
+        None => None,
+    };
+    let activity = match state.db.get_room_activity().await {
+        Ok(activity) => activity,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load rooms").into_response(),
+    };
+
+    let search = query.q.map(|q| q.trim().to_lowercase()).filter(|q| !q.is_empty());
+    let tags: Vec<String> = query.tags.unwrap_or_default()
+        .split(',')
+        .map(|t| t.trim().to_lowercase())
+        .filter(|t| !t.is_empty())
+        .collect();
+    let category = query.category.map(|c| c.trim().to_lowercase()).filter(|c| !c.is_empty());
+
+    let mut rooms: Vec<RoomSummary> = state.rooms.iter()
+        .filter(|r| r.visibility == Visibility::Public)
+        .filter(|r| category.is_none() || r.category == category)
+        .filter(|r| tags.iter().all(|t| r.tags.contains(t)))
+        .filter(|r| search.as_ref().is_none_or(|q| {
+            r.name.to_lowercase().contains(q)
+                || r.description.as_ref().is_some_and(|d| d.to_lowercase().contains(q))
+                || r.tags.iter().any(|t| t.contains(q))
+        }))
+        .map(|r| {
+            let (created_at, last_active_at) = activity.get(&r.id).copied().unwrap_or_default();
+            RoomSummary {
+                id: r.id.clone(),
+                name: r.name.clone(),
+                description: r.description.clone(),
+                category: r.category.clone(),
+                tags: r.tags.clone(),
+                user_count: r.users.len(),
+                max_users: r.max_users,
+                created_at,
+                last_active_at,
+            }
+        })
+        .collect();
+    rooms.sort_by_cached_key(|r| sort_key(r, query.sort));
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut page: Vec<RoomSummary> = rooms.into_iter()
+        .filter(|r| after.as_ref().is_none_or(|after| sort_key(r, query.sort) > *after))
+        .take(limit + 1)
+        .collect();
+    let next_cursor = match page.len() > limit {
+        true => {
+            page.truncate(limit);
+            page.last().map(|r| encode_cursor(&sort_key(r, query.sort)))
+        }
+        false => None,
+    };
+
+    Json(json!({ "rooms": page, "nextCursor": next_cursor })).into_response()
+}
+
+#[derive(Deserialize)]
+pub struct CreateRoom {
+    pub id: Option<String>,
+    pub name: String,
+    #[serde(default)]
+    pub visibility: Visibility,
+    pub password: Option<String>,
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct UpdateRoom {
+    name: Option<String>,
+    visibility: Option<Visibility>,
+    password: Option<String>, // empty string removes the password
+    description: Option<String>, // empty string removes it, like category
+    category: Option<String>,
+    tags: Option<Vec<String>>,
+}
+
+#[derive(Deserialize)]
+pub struct CreateInvite {
+    #[serde(rename = "expiresIn")]
+    expires_in: Option<i64>, // seconds
+}
+
+const DEFAULT_INVITE_TTL: i64 = 24 * 60 * 60;
+const MAX_INVITE_TTL: i64 = 30 * 24 * 60 * 60;
+
+// Ids end up in URLs and Socket.IO room names, where ':' is reserved
+fn is_valid_room_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= 64
+        && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+fn validate_room_name(name: &str) -> Result<String, (StatusCode, &'static str)> {
+    let name = name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return Err((StatusCode::BAD_REQUEST, "Room name must be 1 to 64 characters"));
+    }
+    Ok(name.to_string())
+}
+
+fn validate_description(description: &str) -> Result<Option<String>, (StatusCode, &'static str)> {
+    let description = description.trim();
+    if description.chars().count() > 500 {
+        return Err((StatusCode::BAD_REQUEST, "Description must be at most 500 characters"));
+    }
+    Ok(Some(description.to_string()).filter(|d| !d.is_empty()))
+}
+
+// Categories and tags are matched exactly, so keep them to one spelling
+fn validate_label(label: &str) -> Option<String> {
+    let label = label.trim().to_lowercase();
+    let valid = !label.is_empty()
+        && label.chars().count() <= 32
+        && label.chars().all(|c| c.is_alphanumeric() || c == '-' || c == '_');
+    valid.then_some(label)
+}
+
+fn validate_category(category: &str) -> Result<Option<String>, (StatusCode, &'static str)> {
+    if category.trim().is_empty() {
+        return Ok(None);
+    }
+    validate_label(category)
+        .map(Some)
+        .ok_or((StatusCode::BAD_REQUEST, "Category must be 1 to 32 letters, digits, '-' or '_'"))
+}
+
+fn validate_tags(tags: &[String]) -> Result<Vec<String>, (StatusCode, &'static str)> {
+    let mut valid: Vec<String> = Vec::new();
+    for tag in tags {
+        let Some(tag) = validate_label(tag) else {
+            return Err((StatusCode::BAD_REQUEST, "Tags must be 1 to 32 letters, digits, '-' or '_'"));
+        };
+        if !valid.contains(&tag) {
+            valid.push(tag);
+        }
+    }
+    if valid.len() > 10 {
+        return Err((StatusCode::BAD_REQUEST, "A room can have at most 10 tags"));
+    }
+    Ok(valid)
+}
+
+async fn hash_room_password(password: &str) -> Result<Option<String>, (StatusCode, &'static str)> {
+    if password.is_empty() {
+        return Ok(None);
+    }
+    if password.chars().count() > 128 {
+        return Err((StatusCode::BAD_REQUEST, "Room password must be at most 128 characters"));
+    }
+    auth::hash_password(password.to_string())
+        .await
+        .map(Some)
+        .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password"))
+}
+
+/// Looks up a room the caller is allowed to change.
+fn owned_room(state: &AppState, room_id: &str, user: &AuthUser) -> Result<Room, (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if room.owner_id.as_deref() != Some(user.account_id.as_str()) {
+        return Err((StatusCode::FORBIDDEN, "Only the room's owner can change it"));
+    }
+    Ok(room)
+}
+
+/// Creates a room owned by the caller. Without an `id` one is generated.
+pub async fn create_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Json(payload): Json<CreateRoom>,
+) -> impl IntoResponse {
+    match new_room(&state, &user.account_id, payload).await {
+        Ok(room) => insert_room(&state, &io, room).await,
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Checks the fields of a room about to be created and builds it, empty and
+/// owned by `owner_id`.
+pub async fn new_room(state: &AppState, owner_id: &str, payload: CreateRoom) -> Result<Room, (StatusCode, &'static str)> {
+    let name = validate_room_name(&payload.name)?;
+    let password_hash = match payload.password.as_deref() {
+        Some(password) => hash_room_password(password).await?,
+        None => None,
+    };
+    let description = payload.description.as_deref().map(validate_description).transpose()?.flatten();
+    let category = payload.category.as_deref().map(validate_category).transpose()?.flatten();
+    let tags = validate_tags(&payload.tags)?;
+    let id = payload.id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+    if !is_valid_room_id(&id) {
+        return Err((StatusCode::BAD_REQUEST, "Room id may only contain letters, digits, '-' and '_'"));
+    }
+
+    match state.db.room_exists(&id).await {
+        Ok(false) if !state.rooms.contains_key(&id) => {}
+        Ok(_) => return Err((StatusCode::CONFLICT, "Room already exists")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to create room")),
+    }
+
+    Ok(Room {
+        id,
+        name,
+        users: Vec::new(),
+        objects: Vec::new(),
+        background: None,
+        owner_id: Some(owner_id.to_string()),
+        visibility: payload.visibility,
+        password_hash,
+        roles: HashMap::new(),
+        max_users: None,
+        knock: false,
+        description,
+        category,
+        tags,
+    })
+}
+
+/// Stores a room built by `new_room`, with whatever was put in it since, and
+/// announces it.
+pub async fn insert_room(state: &AppState, io: &SocketIo, room: Room) -> axum::response::Response {
+    match state.db.insert_room(&room).await {
+        Ok(()) => announce_room(state, io, room).await,
+        // Created by someone else since `new_room` looked
+        Err(e) if db::is_unique_violation(&e) => (StatusCode::CONFLICT, "Room already exists").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create room").into_response(),
+    }
+}
+
+/// Puts a room that was just stored among the live ones and tells everyone.
+pub async fn announce_room(state: &AppState, io: &SocketIo, room: Room) -> axum::response::Response {
+    let live = match state.rooms.entry(room.id.clone()) {
+        Entry::Vacant(entry) => {
+            entry.insert(room.clone());
+            None
+        }
+        Entry::Occupied(entry) => Some(entry.get().clone()),
+    };
+    // Someone joined the id, creating it without an owner, after `new_room`
+    // looked. Their room is the one people are in, so it keeps the id.
+    if let Some(live) = live {
+        if state.db.delete_room(&room.id).await.is_err() || state.db.insert_room(&live).await.is_err() {
+            warn!("Failed to give room {} back to the people in it", room.id);
+        }
+        return (StatusCode::CONFLICT, "Room already exists").into_response();
+    }
+    let _ = io.emit("active_rooms", handlers::active_rooms(state));
+
+    (StatusCode::CREATED, Json(room)).into_response()
+}
+
+/// Private rooms look missing to anyone but their owner and the accounts
+/// with a role in them, and a password hides what's inside.
+pub async fn get_room(State(state): State<AppState>, user: AuthUser, Path(room_id): Path<String>) -> impl IntoResponse {
+    let Some(room) = state.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+    match state.check_room_access(&room, Some(&user.account_id), None) {
+        Ok(()) => Json(room).into_response(),
+        Err("invite_required") => (StatusCode::NOT_FOUND, "Room not found").into_response(),
+        Err(_) => (StatusCode::FORBIDDEN, "Room is password protected").into_response(),
+    }
+}
+
+pub async fn update_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    Json(payload): Json<UpdateRoom>,
+) -> impl IntoResponse {
+    let mut room = match owned_room(&state, &room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+
+    if let Some(name) = payload.name {
+        room.name = match validate_room_name(&name) {
+            Ok(name) => name,
+            Err(error) => return error.into_response(),
+        };
+    }
+    if let Some(visibility) = payload.visibility {
+        room.visibility = visibility;
+    }
+    if let Some(password) = payload.password {
+        room.password_hash = match hash_room_password(&password).await {
+            Ok(hash) => hash,
+            Err(error) => return error.into_response(),
+        };
+    }
+    if let Some(description) = payload.description {
+        room.description = match validate_description(&description) {
+            Ok(description) => description,
+            Err(error) => return error.into_response(),
+        };
+    }
+    if let Some(category) = payload.category {
+        room.category = match validate_category(&category) {
+            Ok(category) => category,
+            Err(error) => return error.into_response(),
+        };
+    }
+    if let Some(tags) = payload.tags {
+        room.tags = match validate_tags(&tags) {
+            Ok(tags) => tags,
+            Err(error) => return error.into_response(),
+        };
+    }
+
+    if state.db.update_room(&room).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update room").into_response();
+    }
+    let room = state.update_room(&room).unwrap_or(room);
+
+    let update = json!({
+        "id": room.id,
+        "name": room.name,
+        "visibility": room.visibility,
+        "description": room.description,
+        "category": room.category,
+        "tags": room.tags,
+    });
+    state.buffer_event(&room_id, "room_updated", &update);
+    let _ = io.within(room_id).emit("room_updated", update);
+    let _ = io.emit("active_rooms", handlers::active_rooms(&state));
+
+    Json(room).into_response()
+}
+
+/// Deletes the room with its objects and chat history. Anyone still in it is
+/// told with `room_deleted` and taken out of it.
+pub async fn delete_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+) -> impl IntoResponse {
+    if let Err(error) = owned_room(&state, &room_id, &user) {
+        return error.into_response();
+    }
+
+    if state.db.delete_room(&room_id).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete room").into_response();
+    }
+    state.remove_room(&room_id);
+
+    let _ = io.within(room_id.clone()).emit("room_deleted", json!({ "id": room_id }));
+    let _ = io.within(room_id.clone()).leave(room_id);
+    let _ = io.emit("active_rooms", handlers::active_rooms(&state));
+
+    (StatusCode::OK, "Room deleted").into_response()
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    before: Option<i64>, // timestamp (ms) of the oldest message already loaded
+    #[serde(rename = "beforeId")]
+    before_id: Option<String>, // and its id, for messages sent in the same millisecond
+    limit: Option<usize>,
+}
+
+const MAX_HISTORY_PAGE_SIZE: usize = 100;
+
+/// Chat history is for anyone who could join the room.
+pub async fn readable_room(state: &AppState, room_id: &str, user: &AuthUser) -> Result<(), (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    match state.check_room_access(&room, Some(&user.account_id), None) {
+        Ok(()) => {}
+        Err("invite_required") => return Err((StatusCode::NOT_FOUND, "Room not found")),
+        Err(_) => return Err((StatusCode::FORBIDDEN, "Room is password protected")),
+    }
+    match state.db.find_ban(room_id, &user.account_id, chrono::Utc::now().timestamp()).await {
+        Ok(None) => Ok(()),
+        Ok(Some(_)) => Err((StatusCode::FORBIDDEN, "Banned from this room")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to check room access")),
+    }
+}
+
+/// A page of the room's chat history. Replies are only counted here, see
+/// `get_thread`.
+pub async fn get_messages(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    if let Err(error) = readable_room(&state, &room_id, &user).await {
+        return error.into_response();
     }
 
-    // TODO: Also fetch persistent rooms from DB if not in memory?
-    // For now, let's stick to active rooms or maybe fetch all from DB and merge count.
-    // Let's just return active rooms for this "Live" dashboard feel.
-    
-    Json(rooms)
+    let limit = query.limit.unwrap_or(handlers::HISTORY_PAGE_SIZE).clamp(1, MAX_HISTORY_PAGE_SIZE);
+    match handlers::history_page(&state, &room_id, query.before, query.before_id.as_deref(), limit).await {
+        Ok(page) => Json(page).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load messages").into_response(),
+    }
+}
+
+/// A page of the replies to a message, with the message itself.
+pub async fn get_thread(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((room_id, message_id)): Path<(String, String)>,
+    Query(query): Query<HistoryQuery>,
+) -> impl IntoResponse {
+    if let Err(error) = readable_room(&state, &room_id, &user).await {
+        return error.into_response();
+    }
+
+    let limit = query.limit.unwrap_or(handlers::HISTORY_PAGE_SIZE).clamp(1, MAX_HISTORY_PAGE_SIZE);
+    match chat::thread_page(&state, &room_id, &message_id, query.before, query.before_id.as_deref(), limit).await {
+        Ok(page) => Json(page).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// Signs an invite that lets its holder join the room until it expires,
+/// bypassing its visibility and password.
+pub async fn create_invite(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    Json(payload): Json<CreateInvite>,
+) -> impl IntoResponse {
+    if let Err(error) = owned_room(&state, &room_id, &user) {
+        return error.into_response();
+    }
+
+    let expires_in = payload.expires_in.unwrap_or(DEFAULT_INVITE_TTL);
+    if !(1..=MAX_INVITE_TTL).contains(&expires_in) {
+        return (StatusCode::BAD_REQUEST, "expiresIn must be between 1 second and 30 days").into_response();
+    }
+    let expires_at = chrono::Utc::now().timestamp() + expires_in;
+
+    match auth::issue_invite_token(&state.keys, &room_id, expires_at) {
+        Ok(token) => Json(json!({ "token": token, "expiresAt": expires_at })).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create invite").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyRing;
+
+    fn add_room(state: &AppState, id: &str, name: &str, visibility: &str) {
+        let room: Room = serde_json::from_value(json!({
+            "id": id,
+            "name": name,
+            "users": [],
+            "visibility": visibility,
+        }))
+        .unwrap();
+        state.rooms.insert(id.to_string(), room);
+    }
+
+    async fn page(state: &AppState, cursor: Option<String>) -> axum::response::Response {
+        let query = RoomQuery {
+            q: None,
+            tags: None,
+            category: None,
+            sort: RoomSort::Name,
+            limit: Some(2),
+            cursor,
+        };
+        list_rooms(State(state.clone()), Query(query)).await.into_response()
+    }
+
+    #[tokio::test]
+    async fn room_directory_pages_through_every_public_room_once() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        add_room(&state, "r1", "Beta", "public");
+        add_room(&state, "r2", "alpha", "public");
+        add_room(&state, "r3", "Alpha", "public");
+        add_room(&state, "r4", "Gamma", "public");
+        add_room(&state, "r5", "Hidden", "private");
+        add_room(&state, "r6", "Delta", "unlisted");
+
+        let mut seen = Vec::new();
+        let mut cursor = None;
+        loop {
+            let response = page(&state, cursor).await;
+            assert_eq!(response.status(), StatusCode::OK);
+            let body: serde_json::Value =
+                serde_json::from_slice(&axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap()).unwrap();
+            seen.extend(body["rooms"].as_array().unwrap().iter().map(|r| r["id"].as_str().unwrap().to_string()));
+            cursor = body["nextCursor"].as_str().map(String::from);
+            if cursor.is_none() {
+                break;
+            }
+        }
+        // Same names ordered by id
+        assert_eq!(seen, ["r2", "r3", "r1", "r4"]);
+    }
+
+    #[tokio::test]
+    async fn garbage_cursors_are_refused() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        assert_eq!(page(&state, Some("not a cursor".into())).await.status(), StatusCode::BAD_REQUEST);
+    }
+
+    async fn stored_owner(state: &AppState, room_id: &str) -> Option<String> {
+        state.db.get_rooms().await.unwrap().into_iter().find(|r| r.id == room_id).unwrap().owner_id
+    }
+
+    #[tokio::test]
+    async fn creating_a_taken_room_id_never_overwrites_it() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        let (_, io) = SocketIo::new_layer();
+        io.ns("/", || {});
+        for account in ["acc-1", "acc-2"] {
+            state.db.create_account(account, account, "hash").await.unwrap();
+        }
+        let payload = || CreateRoom {
+            id: Some("lounge".into()),
+            name: "Lounge".into(),
+            visibility: Visibility::Public,
+            password: None,
+            description: None,
+            category: None,
+            tags: Vec::new(),
+        };
+
+        // Both pass the existence check before either is stored
+        let first = new_room(&state, "acc-1", payload()).await.unwrap();
+        let second = new_room(&state, "acc-2", payload()).await.unwrap();
+        assert_eq!(insert_room(&state, &io, first).await.status(), StatusCode::CREATED);
+        assert_eq!(insert_room(&state, &io, second).await.status(), StatusCode::CONFLICT);
+        assert_eq!(stored_owner(&state, "lounge").await.as_deref(), Some("acc-1"));
+        assert_eq!(state.get_room("lounge").unwrap().owner_id.as_deref(), Some("acc-1"));
+    }
+
+    #[tokio::test]
+    async fn a_room_joined_into_existence_meanwhile_keeps_its_id() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        let (_, io) = SocketIo::new_layer();
+        io.ns("/", || {});
+        state.db.create_account("acc-1", "acc-1", "hash").await.unwrap();
+        let room = new_room(&state, "acc-1", CreateRoom {
+            id: Some("lounge".into()),
+            name: "Lounge".into(),
+            visibility: Visibility::Private,
+            password: None,
+            description: None,
+            category: None,
+            tags: Vec::new(),
+        }).await.unwrap();
+
+        // Only the live room exists, its own save hasn't happened yet
+        add_room(&state, "lounge", "Room lounge", "public");
+        assert_eq!(insert_room(&state, &io, room).await.status(), StatusCode::CONFLICT);
+        assert_eq!(state.get_room("lounge").unwrap().owner_id, None);
+        assert_eq!(stored_owner(&state, "lounge").await, None);
+    }
 }
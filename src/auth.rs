@@ -1,15 +1,31 @@
 use axum::{
-    extract::State,
-    http::StatusCode,
-    response::IntoResponse,
-    Json,
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts, Query, Request, State},
+    http::{header::{AUTHORIZATION, RETRY_AFTER}, request::Parts, HeaderMap, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension, Json,
 };
 use serde::{Deserialize, Serialize};
+use crate::keys::KeyRing;
 use crate::state::AppState;
+use crate::two_factor;
 use bcrypt::{hash, verify, DEFAULT_COST};
-use jsonwebtoken::{encode, EncodingKey, Header};
+use jsonwebtoken::errors::ErrorKind;
+use sha2::{Digest, Sha256};
+use serde_json::json;
+use socketioxide::SocketIo;
+use std::net::SocketAddr;
+use std::sync::OnceLock;
+use std::time::Duration;
 use uuid::Uuid;
 
+const ACCESS_TOKEN_TTL: i64 = 15 * 60; // 15 minutes
+const REFRESH_TOKEN_TTL: i64 = 30 * 24 * 3600; // 30 days
+const CHALLENGE_TTL: i64 = 5 * 60; // time to type in a 2FA code
+const GUEST_TOKEN_TTL: i64 = 30 * 24 * 3600; // re-issued on every connect
+const RECENT_AUTH: i64 = 5 * 60; // how fresh a login must be to change how the account logs in
+
 #[derive(Deserialize)]
 pub struct AuthPayload {
     username: String,
@@ -18,77 +34,608 @@ pub struct AuthPayload {
 
 #[derive(Serialize)]
 pub struct AuthResponse {
-    token: String,
+    pub token: String,
+    #[serde(rename = "refreshToken")]
+    pub refresh_token: String,
+    pub username: String,
+}
+
+#[derive(Deserialize)]
+pub struct RefreshPayload {
+    #[serde(rename = "refreshToken")]
+    refresh_token: String,
+}
+
+#[derive(Deserialize)]
+pub struct TwoFactorPayload {
+    challenge: String,
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct LogoutParams {
+    #[serde(default)]
+    all: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Claims {
+    pub sub: String, // account id
+    pub username: String,
+    pub sid: String, // session (refresh token) the access token was issued under
+    pub exp: usize,
+}
+
+pub fn issue_token(keys: &KeyRing, account_id: &str, username: &str, session_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    let expiration = chrono::Utc::now().timestamp() + ACCESS_TOKEN_TTL;
+
+    let claims = Claims {
+        sub: account_id.to_string(),
+        username: username.to_string(),
+        sid: session_id.to_string(),
+        exp: expiration as usize,
+    };
+
+    keys.sign(&claims)
+}
+
+/// Proves the password step of a login succeeded for an account with 2FA.
+/// Has no `sid`, so it can't pass as an access token.
+#[derive(Debug, Serialize, Deserialize)]
+struct ChallengeClaims {
+    sub: String,
     username: String,
+    purpose: String,
+    exp: usize,
 }
 
-#[derive(Serialize, Deserialize)]
-struct Claims {
-    sub: String, // username
+/// Lets a guest keep the same user id across reconnects and tabs.
+#[derive(Debug, Serialize, Deserialize)]
+struct GuestClaims {
+    sub: String,
+    guest: bool,
     exp: usize,
 }
 
+pub fn issue_guest_token(keys: &KeyRing, guest_id: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    keys.sign(&GuestClaims {
+        sub: guest_id.to_string(),
+        guest: true,
+        exp: (chrono::Utc::now().timestamp() + GUEST_TOKEN_TTL) as usize,
+    })
+}
+
+/// Returns the guest id a guest token was issued for.
+pub fn verify_guest_token(keys: &KeyRing, token: &str) -> Option<String> {
+    keys.verify::<GuestClaims>(token)
+        .ok()
+        .filter(|claims| claims.guest)
+        .map(|claims| claims.sub)
+}
+
+/// Grants joining one room, whatever its visibility or password.
+#[derive(Debug, Serialize, Deserialize)]
+struct InviteClaims {
+    room: String,
+    exp: usize,
+}
+
+pub fn issue_invite_token(keys: &KeyRing, room_id: &str, expires_at: i64) -> Result<String, jsonwebtoken::errors::Error> {
+    keys.sign(&InviteClaims {
+        room: room_id.to_string(),
+        exp: expires_at as usize,
+    })
+}
+
+pub fn verify_invite_token(keys: &KeyRing, token: &str, room_id: &str) -> bool {
+    keys.verify::<InviteClaims>(token).is_ok_and(|claims| claims.room == room_id)
+}
+
+/// Hex SHA-256 of a token. Refresh tokens are only ever stored in this form.
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Compares two secrets in time that depends only on their lengths, so a
+/// mismatch doesn't reveal how many leading bytes were right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+fn new_refresh_token() -> String {
+    rand::random::<[u8; 32]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Creates a session for the account and returns its first token pair.
+pub async fn start_session(state: &AppState, account_id: &str, username: &str) -> Result<AuthResponse, StatusCode> {
+    start_session_at(state, account_id, username, Some(chrono::Utc::now().timestamp())).await
+}
+
+/// Like `start_session`, for a login where the user proved who they are at
+/// `authenticated_at`, or at an unknown time.
+pub async fn start_session_at(
+    state: &AppState,
+    account_id: &str,
+    username: &str,
+    authenticated_at: Option<i64>,
+) -> Result<AuthResponse, StatusCode> {
+    let session_id = Uuid::new_v4().to_string();
+    let refresh_token = new_refresh_token();
+    let expires_at = chrono::Utc::now().timestamp() + REFRESH_TOKEN_TTL;
+
+    state.db.create_session(&session_id, account_id, &hash_token(&refresh_token), expires_at, authenticated_at)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = issue_token(&state.keys, account_id, username, &session_id)
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(AuthResponse {
+        token,
+        refresh_token,
+        username: username.to_string(),
+    })
+}
+
+/// Whether the caller's session comes from a login in the last few minutes,
+/// rather than from a token that may have been lying around.
+pub async fn recently_authenticated(state: &AppState, user: &AuthUser) -> Result<bool, sqlx::Error> {
+    let authenticated_at = state.db.get_session_authenticated_at(&user.session_id).await?;
+    Ok(authenticated_at.is_some_and(|at| chrono::Utc::now().timestamp() - at <= RECENT_AUTH))
+}
+
+/// Tells the sockets in a Socket.IO room (`session:<id>` or `account:<id>`)
+/// that their session is gone, then drops them.
+pub fn disconnect_sockets(io: &SocketIo, room: String) {
+    let _ = io.to(room.clone()).emit("session_revoked", ());
+    let _ = io.to(room).disconnect();
+}
+
+/// Decodes a token minted by `login`, checking its signature and expiry.
+pub fn verify_token(keys: &KeyRing, token: &str) -> Result<Claims, jsonwebtoken::errors::Error> {
+    keys.verify(token)
+}
+
+/// The account behind a request's `Authorization: Bearer <token>` header.
+/// Use it as a handler argument on any route that needs a logged-in caller.
+#[derive(Debug, Clone, Serialize)]
+pub struct AuthUser {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    pub username: String,
+    #[serde(skip)]
+    pub session_id: String,
+}
+
+#[async_trait]
+impl FromRequestParts<AppState> for AuthUser {
+    type Rejection = (StatusCode, &'static str);
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        // Already decoded by `require_auth` when the route sits behind it
+        if let Some(user) = parts.extensions.get::<AuthUser>() {
+            return Ok(user.clone());
+        }
+
+        let token = parts.headers
+            .get(AUTHORIZATION)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.strip_prefix("Bearer "))
+            .ok_or((StatusCode::UNAUTHORIZED, "Missing bearer token"))?;
+
+        let claims = verify_token(&state.keys, token.trim()).map_err(|e| match e.kind() {
+            ErrorKind::ExpiredSignature => (StatusCode::UNAUTHORIZED, "Token expired"),
+            _ => (StatusCode::UNAUTHORIZED, "Invalid token"),
+        })?;
+
+        let active = state.db.is_session_active(&claims.sid)
+            .await
+            .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to check session"))?;
+        if !active {
+            return Err((StatusCode::UNAUTHORIZED, "Session revoked"));
+        }
+
+        Ok(AuthUser {
+            account_id: claims.sub,
+            username: claims.username,
+            session_id: claims.sid,
+        })
+    }
+}
+
+/// Route layer that rejects unauthenticated requests with 401 and makes the
+/// decoded `AuthUser` available to the handlers behind it.
+pub async fn require_auth(user: AuthUser, mut req: Request, next: Next) -> Response {
+    req.extensions_mut().insert(user);
+    next.run(req).await
+}
+
+pub async fn me(user: AuthUser) -> impl IntoResponse {
+    Json(user)
+}
+
+#[derive(Serialize)]
+pub struct FieldError {
+    field: &'static str,
+    message: String,
+}
+
+pub fn validate_username(username: &str) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    let len = username.chars().count();
+    if !(3..=32).contains(&len) {
+        errors.push(FieldError { field: "username", message: "Username must be 3-32 characters".to_string() });
+    }
+    if !username.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-' || c == '.') {
+        errors.push(FieldError {
+            field: "username",
+            message: "Username may only contain letters, digits, '_', '-' and '.'".to_string(),
+        });
+    }
+    errors
+}
+
+pub fn validate_password(password: &str, username: &str) -> Vec<FieldError> {
+    let mut errors = Vec::new();
+    if password.chars().count() < 8 {
+        errors.push(FieldError { field: "password", message: "Password must be at least 8 characters".to_string() });
+    }
+    // bcrypt ignores everything past 72 bytes
+    if password.len() > 72 {
+        errors.push(FieldError { field: "password", message: "Password must be at most 72 bytes".to_string() });
+    }
+    if password.eq_ignore_ascii_case(username) {
+        errors.push(FieldError { field: "password", message: "Password must not match the username".to_string() });
+    }
+    errors
+}
+
+pub fn validation_failed(errors: Vec<FieldError>) -> Response {
+    (StatusCode::BAD_REQUEST, Json(json!({
+        "error": "validation_failed",
+        "errors": errors,
+    }))).into_response()
+}
+
+pub fn locked_out(retry_after: Duration) -> Response {
+    let secs = retry_after.as_secs().max(1);
+    (StatusCode::TOO_MANY_REQUESTS, [(RETRY_AFTER, secs.to_string())], Json(json!({
+        "error": "locked_out",
+        "message": "Too many failed login attempts",
+        "retryAfter": secs,
+    }))).into_response()
+}
+
+/// The peer address, or the address the reverse proxy reports when
+/// `TRUST_PROXY_HEADERS` is set (the last `X-Forwarded-For` hop is the one it added).
+pub fn client_ip(headers: &HeaderMap, peer: SocketAddr) -> String {
+    if std::env::var("TRUST_PROXY_HEADERS").is_ok_and(|v| v == "true" || v == "1") {
+        if let Some(ip) = headers.get("x-forwarded-for")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .map(|ip| ip.trim())
+            .filter(|ip| !ip.is_empty())
+        {
+            return ip.to_string();
+        }
+    }
+    peer.ip().to_string()
+}
+
+// Checked against when the username doesn't exist, so that a miss costs
+// as much as a wrong password
+pub fn dummy_hash() -> &'static str {
+    static DUMMY: OnceLock<String> = OnceLock::new();
+    DUMMY.get_or_init(|| hash(Uuid::new_v4().to_string(), DEFAULT_COST).unwrap_or_default())
+}
+
+/// bcrypt is slow on purpose, so it runs on the blocking pool instead of
+/// holding up every socket served by the same worker.
+pub async fn hash_password(password: String) -> Option<String> {
+    tokio::task::spawn_blocking(move || hash(password, DEFAULT_COST).ok())
+        .await
+        .ok()
+        .flatten()
+}
+
+/// Checks `password` against `hash`, or against `dummy_hash` when there is
+/// no account to check it against.
+pub async fn verify_password(password: String, hash: Option<String>) -> bool {
+    tokio::task::spawn_blocking(move || {
+        let hash = hash.as_deref().unwrap_or_else(|| dummy_hash());
+        verify(password, hash).unwrap_or(false)
+    })
+    .await
+    .unwrap_or(false)
+}
+
 pub async fn register(
     State(state): State<AppState>,
     Json(payload): Json<AuthPayload>,
 ) -> impl IntoResponse {
-    // Check if user exists
-    let exists: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM accounts WHERE username = ?)")
-        .bind(&payload.username)
-        .fetch_one(&state.db.pool)
-        .await
-        .unwrap_or(false);
-
-    if exists {
-        return (StatusCode::CONFLICT, "Username already exists").into_response();
+    let mut errors = validate_username(&payload.username);
+    errors.extend(validate_password(&payload.password, &payload.username));
+    if !errors.is_empty() {
+        return validation_failed(errors);
     }
 
-    // Hash password
-    let hashed = hash(payload.password, DEFAULT_COST).unwrap();
+    let Some(hashed) = hash_password(payload.password).await else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create account").into_response();
+    };
     let id = Uuid::new_v4().to_string();
 
     // Save to DB
-    let result = sqlx::query("INSERT INTO accounts (id, username, password_hash) VALUES (?, ?, ?)")
-        .bind(id)
-        .bind(&payload.username)
-        .bind(hashed)
-        .execute(&state.db.pool)
-        .await;
+    let result = sqlx::query(
+        "INSERT INTO accounts (id, username, password_hash) VALUES (?, ?, ?)
+         ON CONFLICT(username) DO NOTHING",
+    )
+    .bind(id)
+    .bind(&payload.username)
+    .bind(hashed)
+    .execute(&state.db.pool)
+    .await;
 
     match result {
+        // The insert is skipped when the name is taken, even by a concurrent registration
+        Ok(done) if done.rows_affected() == 0 => (StatusCode::CONFLICT, Json(json!({
+            "error": "username_taken",
+            "errors": [FieldError { field: "username", message: "Username is already taken".to_string() }],
+        }))).into_response(),
         Ok(_) => (StatusCode::CREATED, "Account created").into_response(),
         Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create account").into_response(),
     }
 }
 
+/// Issued once the first login step went through for an account with 2FA,
+/// to be sent back to `login_2fa` along with a code.
+pub fn issue_challenge(keys: &KeyRing, account_id: &str, username: &str) -> Result<String, jsonwebtoken::errors::Error> {
+    keys.sign(&ChallengeClaims {
+        sub: account_id.to_string(),
+        username: username.to_string(),
+        purpose: "2fa".to_string(),
+        exp: (chrono::Utc::now().timestamp() + CHALLENGE_TTL) as usize,
+    })
+}
+
 pub async fn login(
     State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
     Json(payload): Json<AuthPayload>,
 ) -> impl IntoResponse {
-    let row: Option<(String, String)> = sqlx::query_as("SELECT username, password_hash FROM accounts WHERE username = ?")
+    // Counted by the name that was tried, whether or not such an account exists
+    let account_key = payload.username.to_lowercase();
+    let ip_key = client_ip(&headers, peer);
+    if let Some(retry_after) = state.account_throttle.check(&account_key)
+        .into_iter()
+        .chain(state.ip_throttle.check(&ip_key))
+        .max()
+    {
+        return locked_out(retry_after);
+    }
+
+    let row: Option<(String, String, String)> = sqlx::query_as("SELECT id, username, password_hash FROM accounts WHERE username = ?")
         .bind(&payload.username)
         .fetch_optional(&state.db.pool)
         .await
         .unwrap_or(None);
 
-    if let Some((username, hash)) = row {
-        if verify(payload.password, &hash).unwrap_or(false) {
-            // Generate JWT
-            let expiration = std::time::SystemTime::now()
-                .duration_since(std::time::UNIX_EPOCH)
-                .unwrap()
-                .as_secs() as usize + 3600 * 24; // 24 hours
-
-            let claims = Claims {
-                sub: username.clone(),
-                exp: expiration,
-            };
+    let (account, hash) = match row {
+        Some((id, username, hash)) => (Some((id, username)), Some(hash)),
+        None => (None, None),
+    };
 
-            let token = encode(&Header::default(), &claims, &EncodingKey::from_secret("secret".as_ref())).unwrap();
+    if verify_password(payload.password, hash).await {
+        if let Some((id, username)) = account {
+            match two_factor::is_enabled(&state, &id).await {
+                // The throttle is only reset once the second factor is in too
+                Ok(true) => {
+                    return match issue_challenge(&state.keys, &id, &username) {
+                        Ok(challenge) => Json(json!({
+                            "twoFactorRequired": true,
+                            "challenge": challenge,
+                        })).into_response(),
+                        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to start login").into_response(),
+                    };
+                }
+                Ok(false) => {}
+                Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to start login").into_response(),
+            }
 
-            return Json(AuthResponse { token, username }).into_response();
+            state.account_throttle.reset(&account_key);
+            return match start_session(&state, &id, &username).await {
+                Ok(response) => Json(response).into_response(),
+                Err(status) => (status, "Failed to start session").into_response(),
+            };
         }
     }
 
+    state.account_throttle.record_failure(&account_key);
+    state.ip_throttle.record_failure(&ip_key);
     (StatusCode::UNAUTHORIZED, "Invalid credentials").into_response()
 }
+
+/// Second step of a login for accounts with 2FA: trades the `challenge` from
+/// `login` plus a TOTP or recovery code for a token pair.
+pub async fn login_2fa(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Json(payload): Json<TwoFactorPayload>,
+) -> impl IntoResponse {
+    let claims = match state.keys.verify::<ChallengeClaims>(&payload.challenge) {
+        Ok(claims) if claims.purpose == "2fa" => claims,
+        _ => return (StatusCode::UNAUTHORIZED, "Invalid or expired challenge").into_response(),
+    };
+
+    let account_key = claims.username.to_lowercase();
+    let ip_key = client_ip(&headers, peer);
+    if let Some(retry_after) = state.account_throttle.check(&account_key)
+        .into_iter()
+        .chain(state.ip_throttle.check(&ip_key))
+        .max()
+    {
+        return locked_out(retry_after);
+    }
+
+    match two_factor::verify_code(&state, &claims.sub, &claims.username, &payload.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            state.account_throttle.record_failure(&account_key);
+            state.ip_throttle.record_failure(&ip_key);
+            return (StatusCode::UNAUTHORIZED, "Invalid code").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify code").into_response(),
+    }
+
+    state.account_throttle.reset(&account_key);
+    match start_session(&state, &claims.sub, &claims.username).await {
+        Ok(response) => Json(response).into_response(),
+        Err(status) => (status, "Failed to start session").into_response(),
+    }
+}
+
+/// Trades a refresh token for a new access token. The refresh token is
+/// rotated, so each one can only be used once.
+pub async fn refresh(
+    State(state): State<AppState>,
+    Json(payload): Json<RefreshPayload>,
+) -> impl IntoResponse {
+    let old_hash = hash_token(&payload.refresh_token);
+    let session = state.db.find_session_by_refresh(&old_hash, chrono::Utc::now().timestamp())
+        .await
+        .unwrap_or(None);
+
+    let Some((session_id, account_id, username)) = session else {
+        return (StatusCode::UNAUTHORIZED, "Invalid refresh token").into_response();
+    };
+
+    let refresh_token = new_refresh_token();
+    let expires_at = chrono::Utc::now().timestamp() + REFRESH_TOKEN_TTL;
+    match state.db.rotate_session(&session_id, &old_hash, &hash_token(&refresh_token), expires_at).await {
+        Ok(true) => {}
+        // Someone else used this refresh token first
+        Ok(false) => return (StatusCode::UNAUTHORIZED, "Invalid refresh token").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to refresh session").into_response(),
+    }
+
+    match issue_token(&state.keys, &account_id, &username, &session_id) {
+        Ok(token) => Json(AuthResponse { token, refresh_token, username }).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to refresh session").into_response(),
+    }
+}
+
+/// Revokes the caller's session, or every session of the account with `?all=true`,
+/// and disconnects the sockets that were using them.
+pub async fn logout(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Query(params): Query<LogoutParams>,
+) -> impl IntoResponse {
+    let result = if params.all {
+        state.db.revoke_account_sessions(&user.account_id).await
+    } else {
+        state.db.revoke_session(&user.session_id).await
+    };
+
+    if result.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to log out").into_response();
+    }
+
+    if params.all {
+        disconnect_sockets(&io, format!("account:{}", user.account_id));
+    } else {
+        disconnect_sockets(&io, format!("session:{}", user.session_id));
+    }
+
+    (StatusCode::OK, "Logged out").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn test_state() -> AppState {
+        AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap()
+    }
+
+    #[test]
+    fn hash_token_is_hex_sha256() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+
+    #[tokio::test]
+    async fn refresh_tokens_work_once() {
+        let state = test_state().await;
+        state.db.create_account("acc-1", "alice", "hash").await.unwrap();
+        let session = start_session(&state, "acc-1", "alice").await.unwrap();
+
+        let refresh_with = |token: String| {
+            let state = state.clone();
+            async move {
+                refresh(State(state), Json(RefreshPayload { refresh_token: token })).await.into_response().status()
+            }
+        };
+        assert_eq!(refresh_with(session.refresh_token.clone()).await, StatusCode::OK);
+        assert_eq!(refresh_with(session.refresh_token).await, StatusCode::UNAUTHORIZED);
+    }
+
+    fn messages(errors: Vec<FieldError>) -> Vec<String> {
+        errors.into_iter().map(|e| e.message).collect()
+    }
+
+    #[test]
+    fn usernames_are_checked_for_length_and_characters() {
+        assert!(validate_username("alice.b-2_c").is_empty());
+        assert_eq!(messages(validate_username("al")), ["Username must be 3-32 characters"]);
+        assert_eq!(messages(validate_username(&"a".repeat(33))), ["Username must be 3-32 characters"]);
+        assert_eq!(
+            messages(validate_username("al ice")),
+            ["Username may only contain letters, digits, '_', '-' and '.'"],
+        );
+    }
+
+    #[test]
+    fn passwords_are_checked_for_length_and_the_username() {
+        assert!(validate_password("correct horse", "alice").is_empty());
+        assert_eq!(messages(validate_password("short", "alice")), ["Password must be at least 8 characters"]);
+        // 37 two-byte characters pass the character count but not bcrypt's byte limit
+        assert_eq!(messages(validate_password(&"é".repeat(37), "alice")), ["Password must be at most 72 bytes"]);
+        assert_eq!(messages(validate_password("Alice123", "alice123")), ["Password must not match the username"]);
+    }
+
+    #[test]
+    fn tokens_only_pass_as_their_own_kind() {
+        let keys = KeyRing::from_env().unwrap();
+        let access = issue_token(&keys, "acc-1", "alice", "session-1").unwrap();
+        let claims = verify_token(&keys, &access).unwrap();
+        assert_eq!((claims.sub.as_str(), claims.sid.as_str()), ("acc-1", "session-1"));
+
+        let guest = issue_guest_token(&keys, "guest-1").unwrap();
+        let challenge = issue_challenge(&keys, "acc-1", "alice").unwrap();
+        let invite = issue_invite_token(&keys, "room-1", chrono::Utc::now().timestamp() + 60).unwrap();
+        assert!(verify_token(&keys, &guest).is_err());
+        assert!(verify_token(&keys, &challenge).is_err());
+        assert!(verify_token(&keys, &invite).is_err());
+
+        assert_eq!(verify_guest_token(&keys, &guest).as_deref(), Some("guest-1"));
+        assert_eq!(verify_guest_token(&keys, &access), None);
+        assert!(verify_invite_token(&keys, &invite, "room-1"));
+        assert!(!verify_invite_token(&keys, &invite, "room-2"));
+    }
+
+    #[test]
+    fn constant_time_eq_compares_whole_values() {
+        assert!(constant_time_eq(b"123456", b"123456"));
+        assert!(!constant_time_eq(b"123456", b"123457"));
+        assert!(!constant_time_eq(b"123456", b"12345"));
+        assert!(constant_time_eq(b"", b""));
+    }
+
+}
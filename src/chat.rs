@@ -0,0 +1,119 @@
+use axum::http::StatusCode;
+use serde_json::json;
+use crate::roles::Permission;
+use crate::state::AppState;
+use crate::types::ChatMessage;
+
+// Different emoji a single message can be reacted to with
+const MAX_REACTION_EMOJI: usize = 20;
+const MAX_EMOJI_CHARS: usize = 16;
+
+/// A message of the room that hasn't been deleted.
+async fn live_message(state: &AppState, room_id: &str, message_id: &str) -> Result<ChatMessage, &'static str> {
+    match state.db.get_message(message_id).await {
+        Ok(Some((room, message))) if room == room_id && message.deleted_at.is_none() => Ok(message),
+        Ok(_) => Err("Message not found"),
+        Err(_) => Err("Failed to load message"),
+    }
+}
+
+/// Authors can edit their own messages.
+pub async fn edit(state: &AppState, room_id: &str, message_id: &str, user_id: &str, text: &str) -> Result<ChatMessage, &'static str> {
+    if text.trim().is_empty() {
+        return Err("Message can't be empty");
+    }
+    let mut message = live_message(state, room_id, message_id).await?;
+    if message.user_id != user_id {
+        return Err("Only the author can edit this message");
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    match state.db.edit_message(message_id, text, now).await {
+        Ok(true) => {}
+        Ok(false) => return Err("Message not found"),
+        Err(_) => return Err("Failed to edit message"),
+    }
+    message.text = text.to_string();
+    message.edited_at = Some(now);
+    Ok(message)
+}
+
+/// Authors can delete their own messages, and moderators of owned rooms
+/// anyone's. Returns the message as deleted.
+pub async fn delete(
+    state: &AppState,
+    room_id: &str,
+    message_id: &str,
+    user_id: &str,
+    account_id: Option<&str>,
+) -> Result<ChatMessage, &'static str> {
+    let mut message = live_message(state, room_id, message_id).await?;
+    let moderator = state.get_room(room_id)
+        .is_some_and(|room| room.allows(account_id, Permission::Moderate));
+    if message.user_id != user_id && !moderator {
+        return Err("Only the author or a moderator can delete this message");
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    match state.db.delete_message(message_id, now).await {
+        Ok(true) => {
+            message.deleted_at = Some(now);
+            Ok(message)
+        }
+        Ok(false) => Err("Message not found"),
+        Err(_) => Err("Failed to delete message"),
+    }
+}
+
+/// The message a reply to `parent_id` goes under: the parent itself, or the
+/// message it is a reply to, as threads don't nest.
+pub async fn thread_root(state: &AppState, room_id: &str, parent_id: &str) -> Result<String, &'static str> {
+    let parent = live_message(state, room_id, parent_id).await?;
+    Ok(parent.parent_id.unwrap_or(parent.id))
+}
+
+/// Adds the user's reaction to a message, or takes it away if they had
+/// already reacted with that emoji. Returns whether it was added and
+/// everyone who has now reacted with it.
+pub async fn react(state: &AppState, room_id: &str, message_id: &str, user_id: &str, emoji: &str) -> Result<(bool, Vec<String>), &'static str> {
+    let emoji = emoji.trim();
+    if emoji.is_empty() || emoji.chars().count() > MAX_EMOJI_CHARS {
+        return Err("Invalid reaction");
+    }
+    let message = live_message(state, room_id, message_id).await?;
+    if message.reactions.len() >= MAX_REACTION_EMOJI && !message.reactions.iter().any(|r| r.emoji == emoji) {
+        return Err("Too many different reactions on this message");
+    }
+
+    let now = chrono::Utc::now().timestamp_millis();
+    let added = state.db.toggle_reaction(message_id, user_id, emoji, now).await
+        .map_err(|_| "Failed to react to message")?;
+    let users = state.db.get_reaction_users(message_id, emoji).await
+        .map_err(|_| "Failed to react to message")?;
+    Ok((added, users))
+}
+
+/// A page of a thread: the message replied to, and up to `limit` replies
+/// sent before `before` (and `before_id`, see `history_page`), or the latest
+/// ones, oldest first.
+pub async fn thread_page(
+    state: &AppState,
+    room_id: &str,
+    parent_id: &str,
+    before: Option<i64>,
+    before_id: Option<&str>,
+    limit: usize,
+) -> Result<serde_json::Value, (StatusCode, &'static str)> {
+    let parent = match state.db.get_message(parent_id).await {
+        Ok(Some((room, message))) if room == room_id && message.parent_id.is_none() => message,
+        Ok(_) => return Err((StatusCode::NOT_FOUND, "Thread not found")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load thread")),
+    };
+    let mut replies = state.db.get_replies_before(parent_id, before, before_id, limit as i64 + 1).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load thread"))?;
+    let has_more = replies.len() > limit;
+    if has_more {
+        replies.remove(0);
+    }
+    Ok(json!({ "roomId": room_id, "parent": parent, "before": before, "beforeId": before_id, "replies": replies, "hasMore": has_more }))
+}
@@ -1,6 +1,7 @@
-use sqlx::{sqlite::SqlitePoolOptions, Pool, Sqlite, SqlitePool};
-use crate::types::{Room, RoomObject, User};
-use std::sync::Arc;
+use sqlx::{Executor, Pool, Sqlite, SqliteConnection, SqlitePool};
+use std::collections::HashMap;
+use crate::roles::Role;
+use crate::types::{AccountProfile, DirectMessage, DmConversation, DmMember, Reaction, Report, Room, RoomBan, RoomObject, RoomSnapshot, RoomTemplate, User, Visibility};
 
 #[derive(Clone)]
 pub struct Db {
@@ -15,7 +16,27 @@ impl Db {
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS rooms (
                 id TEXT PRIMARY KEY,
-                name TEXT NOT NULL
+                name TEXT NOT NULL,
+                owner_id TEXT REFERENCES accounts(id),
+                visibility TEXT NOT NULL DEFAULT 'public',
+                password_hash TEXT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "rooms", "owner_id", "TEXT REFERENCES accounts(id)").await?;
+        add_column_if_missing(&pool, "rooms", "visibility", "TEXT NOT NULL DEFAULT 'public'").await?;
+        add_column_if_missing(&pool, "rooms", "password_hash", "TEXT").await?;
+        add_column_if_missing(&pool, "rooms", "description", "TEXT").await?;
+        add_column_if_missing(&pool, "rooms", "category", "TEXT").await?;
+        add_column_if_missing(&pool, "rooms", "created_at", "INTEGER").await?;
+        add_column_if_missing(&pool, "rooms", "last_active_at", "INTEGER").await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_tags (
+                room_id TEXT NOT NULL,
+                tag TEXT NOT NULL,
+                PRIMARY KEY (room_id, tag)
             )",
         )
         .execute(&pool)
@@ -38,6 +59,64 @@ impl Db {
         .execute(&pool)
         .await?;
 
+        // One row per setting, so new settings don't need a migration
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_settings (
+                room_id TEXT NOT NULL,
+                key TEXT NOT NULL,
+                value TEXT NOT NULL,
+                PRIMARY KEY (room_id, key)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_roles (
+                room_id TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                role TEXT NOT NULL,
+                PRIMARY KEY (room_id, account_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Objects are kept as JSON; they only come back out whole
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_templates (
+                id TEXT PRIMARY KEY,
+                name TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                background TEXT,
+                max_users INTEGER,
+                knock INTEGER NOT NULL DEFAULT 0,
+                objects TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_snapshots (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                label TEXT,
+                created_by TEXT,
+                background TEXT,
+                max_users INTEGER,
+                knock INTEGER NOT NULL DEFAULT 0,
+                objects TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS room_snapshots_room ON room_snapshots (room_id, created_at)")
+            .execute(&pool)
+            .await?;
+
         sqlx::query(
             "CREATE TABLE IF NOT EXISTS users (
                 id TEXT PRIMARY KEY,
@@ -45,7 +124,53 @@ impl Db {
                 color TEXT NOT NULL,
                 x REAL NOT NULL,
                 y REAL NOT NULL,
-                room_id TEXT
+                room_id TEXT,
+                account_id TEXT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "users", "account_id", "TEXT").await?;
+
+        // Mutes stick to the room, so leaving and coming back doesn't lift them
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_mutes (
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                chat INTEGER NOT NULL DEFAULT 0,
+                voice INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (room_id, user_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // user_id is the stable user id, so guests can be banned too
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS room_bans (
+                room_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                banned_by TEXT NOT NULL,
+                reason TEXT,
+                expires_at INTEGER,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (room_id, user_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS reports (
+                id TEXT PRIMARY KEY,
+                room_id TEXT NOT NULL,
+                reporter_id TEXT NOT NULL,
+                target_id TEXT NOT NULL,
+                reason TEXT NOT NULL,
+                status TEXT NOT NULL DEFAULT 'open',
+                created_at INTEGER NOT NULL,
+                resolved_by TEXT,
+                resolved_at INTEGER
             )",
         )
         .execute(&pool)
@@ -56,11 +181,60 @@ impl Db {
                 id TEXT PRIMARY KEY,
                 username TEXT NOT NULL UNIQUE,
                 password_hash TEXT NOT NULL,
+                created_at DATETIME DEFAULT CURRENT_TIMESTAMP,
+                display_name TEXT,
+                avatar_color TEXT,
+                bio TEXT,
+                totp_secret TEXT,
+                totp_pending_secret TEXT,
+                totp_last_step INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "accounts", "display_name", "TEXT").await?;
+        add_column_if_missing(&pool, "accounts", "avatar_color", "TEXT").await?;
+        add_column_if_missing(&pool, "accounts", "bio", "TEXT").await?;
+        add_column_if_missing(&pool, "accounts", "totp_secret", "TEXT").await?;
+        add_column_if_missing(&pool, "accounts", "totp_pending_secret", "TEXT").await?;
+        add_column_if_missing(&pool, "accounts", "totp_last_step", "INTEGER").await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS account_identities (
+                issuer TEXT NOT NULL,
+                subject TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                PRIMARY KEY (issuer, subject)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS recovery_codes (
+                account_id TEXT NOT NULL,
+                code_hash TEXT NOT NULL,
+                used INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (account_id, code_hash)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS refresh_tokens (
+                id TEXT PRIMARY KEY,
+                account_id TEXT NOT NULL,
+                token_hash TEXT NOT NULL UNIQUE,
+                expires_at INTEGER NOT NULL,
+                revoked INTEGER NOT NULL DEFAULT 0,
                 created_at DATETIME DEFAULT CURRENT_TIMESTAMP
             )",
         )
         .execute(&pool)
         .await?;
+        // When the user last proved who they are for this session; kept across refreshes
+        add_column_if_missing(&pool, "refresh_tokens", "authenticated_at", "INTEGER").await?;
 
         // Create messages table
         sqlx::query(
@@ -70,11 +244,126 @@ impl Db {
                 user_id TEXT NOT NULL,
                 user_name TEXT NOT NULL,
                 text TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                account_id TEXT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        add_column_if_missing(&pool, "messages", "account_id", "TEXT").await?;
+        add_column_if_missing(&pool, "messages", "edited_at", "INTEGER").await?;
+        add_column_if_missing(&pool, "messages", "deleted_at", "INTEGER").await?;
+        add_column_if_missing(&pool, "messages", "parent_id", "TEXT").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_room_time ON messages (room_id, timestamp)")
+            .execute(&pool)
+            .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS messages_parent ON messages (parent_id, timestamp)")
+            .execute(&pool)
+            .await?;
+
+        // Full-text index over the text of messages that haven't been deleted,
+        // kept up to date by triggers. It reads snippets from `messages` itself,
+        // matching rows by `search_id`: the implicit rowid of a table with a
+        // TEXT primary key can change on VACUUM.
+        if add_column_if_missing(&pool, "messages", "search_id", "INTEGER").await? {
+            sqlx::query("UPDATE messages SET search_id = rowid")
+                .execute(&pool)
+                .await?;
+            // An index from before search_id is keyed on rowid; build it again
+            for statement in [
+                "DROP TRIGGER IF EXISTS messages_fts_insert",
+                "DROP TRIGGER IF EXISTS messages_fts_update",
+                "DROP TRIGGER IF EXISTS messages_fts_delete",
+                "DROP TABLE IF EXISTS messages_fts",
+            ] {
+                sqlx::query(statement).execute(&pool).await?;
+            }
+        }
+        sqlx::query("CREATE UNIQUE INDEX IF NOT EXISTS messages_search_id ON messages (search_id)")
+            .execute(&pool)
+            .await?;
+        let indexed: bool = sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE name = 'messages_fts')")
+            .fetch_one(&pool)
+            .await?;
+        if !indexed {
+            sqlx::query("CREATE VIRTUAL TABLE messages_fts USING fts5(text, content='messages', content_rowid='search_id')")
+                .execute(&pool)
+                .await?;
+            sqlx::query("INSERT INTO messages_fts (rowid, text) SELECT search_id, text FROM messages WHERE deleted_at IS NULL")
+                .execute(&pool)
+                .await?;
+        }
+        for trigger in [
+            "CREATE TRIGGER IF NOT EXISTS messages_fts_insert AFTER INSERT ON messages WHEN new.deleted_at IS NULL BEGIN
+                INSERT INTO messages_fts (rowid, text) VALUES (new.search_id, new.text);
+            END",
+            "CREATE TRIGGER IF NOT EXISTS messages_fts_update AFTER UPDATE OF text, deleted_at ON messages BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, text) SELECT 'delete', old.search_id, old.text WHERE old.deleted_at IS NULL;
+                INSERT INTO messages_fts (rowid, text) SELECT new.search_id, new.text WHERE new.deleted_at IS NULL;
+            END",
+            "CREATE TRIGGER IF NOT EXISTS messages_fts_delete AFTER DELETE ON messages WHEN old.deleted_at IS NULL BEGIN
+                INSERT INTO messages_fts (messages_fts, rowid, text) VALUES ('delete', old.search_id, old.text);
+            END",
+        ] {
+            sqlx::query(trigger).execute(&pool).await?;
+        }
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS message_reactions (
+                message_id TEXT NOT NULL,
+                user_id TEXT NOT NULL,
+                emoji TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (message_id, emoji, user_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Direct messages live apart from rooms. `pair_key` holds the two
+        // account ids of a one-to-one conversation, so there is only one per pair.
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS dm_conversations (
+                id TEXT PRIMARY KEY,
+                name TEXT,
+                pair_key TEXT UNIQUE,
+                created_by TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                last_message_at INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS dm_members (
+                conversation_id TEXT NOT NULL,
+                account_id TEXT NOT NULL,
+                last_read_at INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (conversation_id, account_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS dm_members_account ON dm_members (account_id)")
+            .execute(&pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS dm_messages (
+                id TEXT PRIMARY KEY,
+                conversation_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                sender_name TEXT NOT NULL,
+                text TEXT NOT NULL,
                 timestamp INTEGER NOT NULL
             )",
         )
         .execute(&pool)
         .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS dm_messages_conversation_time ON dm_messages (conversation_id, timestamp)")
+            .execute(&pool)
+            .await?;
 
         Ok(Self { pool })
     }
@@ -115,18 +404,34 @@ impl Db {
         // For simplicity, let's keep rooms simple and load objects.
         // Users are usually transient in memory for socket server, but we want persistence.
         // Let's just load rooms and objects for now as before.
-        let rows = sqlx::query_as::<_, (String, String)>("SELECT id, name FROM rooms")
-            .fetch_all(&self.pool)
-            .await?;
+        let rows = sqlx::query_as::<_, (String, String, Option<String>, String, Option<String>, Option<String>, Option<String>)>(
+            "SELECT id, name, owner_id, visibility, password_hash, description, category FROM rooms"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        let mut tags = self.get_room_tags().await?;
 
         let mut rooms = Vec::new();
         for row in rows {
+            let objects = self.get_room_objects(&row.0).await?;
+            let settings = self.get_room_settings(&row.0).await?;
+            let roles = self.get_room_roles(&row.0).await?;
+            let tags = tags.remove(&row.0).unwrap_or_default();
             rooms.push(Room {
                 id: row.0,
                 name: row.1,
                 users: Vec::new(), // Users will join or be loaded separately if we want "offline" users
-                objects: Vec::new(),
-                background: None,
+                objects,
+                background: setting(&settings, "background").map(str::to_string),
+                owner_id: row.2,
+                visibility: Visibility::parse(&row.3).unwrap_or_default(),
+                password_hash: row.4,
+                roles,
+                max_users: setting(&settings, "max_users").and_then(|v| v.parse().ok()),
+                knock: setting(&settings, "knock") == Some("true"),
+                description: row.5,
+                category: row.6,
+                tags,
             });
         }
         Ok(rooms)
@@ -134,8 +439,8 @@ impl Db {
 
     pub async fn save_user(&self, user: &User, room_id: &str) -> Result<(), sqlx::Error> {
         sqlx::query(
-            "INSERT INTO users (id, name, color, x, y, room_id) VALUES (?, ?, ?, ?, ?, ?)
-             ON CONFLICT(id) DO UPDATE SET name=excluded.name, color=excluded.color, x=excluded.x, y=excluded.y, room_id=excluded.room_id",
+            "INSERT INTO users (id, name, color, x, y, room_id, account_id) VALUES (?, ?, ?, ?, ?, ?, ?)
+             ON CONFLICT(id) DO UPDATE SET name=excluded.name, color=excluded.color, x=excluded.x, y=excluded.y, room_id=excluded.room_id, account_id=excluded.account_id",
         )
         .bind(&user.id)
         .bind(&user.name)
@@ -143,11 +448,35 @@ impl Db {
         .bind(user.x)
         .bind(user.y)
         .bind(room_id)
+        .bind(&user.account_id)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
+    /// Last saved state of a user, by stable user id.
+    pub async fn get_user(&self, id: &str) -> Result<Option<User>, sqlx::Error> {
+        let row = sqlx::query_as::<_, (String, String, String, f64, f64, Option<String>, Option<String>)>(
+            "SELECT id, name, color, x, y, room_id, account_id FROM users WHERE id = ?"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(id, name, color, x, y, room_id, account_id)| User {
+            id,
+            name,
+            color,
+            x,
+            y,
+            room_id: room_id.unwrap_or_default(),
+            account_id,
+            reconnecting: false,
+            chat_muted: false,
+            voice_muted: false,
+        }))
+    }
+
     pub async fn get_room_objects(&self, room_id: &str) -> Result<Vec<RoomObject>, sqlx::Error> {
         let rows = sqlx::query_as::<_, (String, String, f64, f64, f64, f64, String, i32, f64)>(
             r#"
@@ -173,108 +502,1458 @@ impl Db {
         }).collect())
     }
 
-    pub async fn save_room(&self, room: &Room) -> Result<(), sqlx::Error> {
-        // Upsert room
-        sqlx::query(
-            "INSERT INTO rooms (id, name) VALUES (?, ?) ON CONFLICT(id) DO UPDATE SET name = ?"
-        )
-        .bind(&room.id)
-        .bind(&room.name)
-        .bind(&room.name)
+    /// Stores a new room with its objects and settings. Fails with a unique
+    /// violation (see `is_unique_violation`) when the id is taken.
+    pub async fn insert_room(&self, room: &Room) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        write_room(&mut tx, room).await?;
+        write_room_settings(&mut tx, room).await?;
+        tx.commit().await
+    }
+
+    /// Stores an imported room with its settings and chat history, all or nothing.
+    pub async fn import_room(&self, room: &Room, messages: &[crate::types::ChatMessage]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        write_room(&mut tx, room).await?;
+        write_room_settings(&mut tx, room).await?;
+        for message in messages {
+            insert_message(&mut *tx, message, &room.id).await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn get_room_settings(&self, room_id: &str) -> Result<Vec<(String, String)>, sqlx::Error> {
+        sqlx::query_as::<_, (String, String)>("SELECT key, value FROM room_settings WHERE room_id = ?")
+            .bind(room_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Stores one room setting; `None` removes it.
+    pub async fn save_room_setting(&self, room_id: &str, key: &str, value: Option<&str>) -> Result<(), sqlx::Error> {
+        write_room_setting(&self.pool, room_id, key, value).await
+    }
+
+    pub async fn get_room_roles(&self, room_id: &str) -> Result<HashMap<String, Role>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT account_id, role FROM room_roles WHERE room_id = ?")
+            .bind(room_id)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter()
+            .filter_map(|(account_id, role)| Some((account_id, Role::parse(&role)?)))
+            .collect())
+    }
+
+    /// Stores an account's role in a room; `None` takes it away.
+    pub async fn set_room_role(&self, room_id: &str, account_id: &str, role: Option<Role>) -> Result<(), sqlx::Error> {
+        match role {
+            Some(role) => sqlx::query(
+                "INSERT INTO room_roles (room_id, account_id, role) VALUES (?, ?, ?)
+                 ON CONFLICT(room_id, account_id) DO UPDATE SET role = excluded.role"
+            )
+            .bind(room_id)
+            .bind(account_id)
+            .bind(role.as_str()),
+            None => sqlx::query("DELETE FROM room_roles WHERE room_id = ? AND account_id = ?")
+                .bind(room_id)
+                .bind(account_id),
+        }
         .execute(&self.pool)
         .await?;
+        Ok(())
+    }
+
+    /// Returns `(chat, voice)` mute flags of a user in a room.
+    pub async fn get_mutes(&self, room_id: &str, user_id: &str) -> Result<(bool, bool), sqlx::Error> {
+        let row = sqlx::query_as::<_, (bool, bool)>("SELECT chat, voice FROM room_mutes WHERE room_id = ? AND user_id = ?")
+            .bind(room_id)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.unwrap_or((false, false)))
+    }
 
-        for obj in &room.objects {
-            self.save_object(&room.id, obj).await?;
+    pub async fn set_mutes(&self, room_id: &str, user_id: &str, chat: bool, voice: bool) -> Result<(), sqlx::Error> {
+        if !chat && !voice {
+            sqlx::query("DELETE FROM room_mutes WHERE room_id = ? AND user_id = ?")
+                .bind(room_id)
+                .bind(user_id)
+                .execute(&self.pool)
+                .await?;
+            return Ok(());
         }
+        sqlx::query(
+            "INSERT INTO room_mutes (room_id, user_id, chat, voice) VALUES (?, ?, ?, ?)
+             ON CONFLICT(room_id, user_id) DO UPDATE SET chat = excluded.chat, voice = excluded.voice"
+        )
+        .bind(room_id)
+        .bind(user_id)
+        .bind(chat)
+        .bind(voice)
+        .execute(&self.pool)
+        .await?;
         Ok(())
     }
 
-    pub async fn save_object(&self, room_id: &str, obj: &RoomObject) -> Result<(), sqlx::Error> {
+    pub async fn ban_user(&self, room_id: &str, ban: &RoomBan) -> Result<(), sqlx::Error> {
         sqlx::query(
-            r#"
-            INSERT INTO room_objects (id, room_id, type, x, y, width, height, content, z_index, rotation)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
-            ON CONFLICT(id) DO UPDATE SET
-            x = ?, y = ?, width = ?, height = ?, content = ?, z_index = ?, rotation = ?
-            "#
+            "INSERT INTO room_bans (room_id, user_id, banned_by, reason, expires_at, created_at) VALUES (?, ?, ?, ?, ?, ?)
+             ON CONFLICT(room_id, user_id) DO UPDATE SET banned_by = excluded.banned_by, reason = excluded.reason,
+             expires_at = excluded.expires_at, created_at = excluded.created_at"
         )
-        .bind(&obj.id)
         .bind(room_id)
-        .bind(&obj.obj_type)
-        .bind(obj.x)
-        .bind(obj.y)
-        .bind(obj.width)
-        .bind(obj.height)
-        .bind(&obj.content)
-        .bind(obj.z_index)
-        .bind(obj.rotation)
-        // Updates
-        .bind(obj.x)
-        .bind(obj.y)
-        .bind(obj.width)
-        .bind(obj.height)
-        .bind(&obj.content)
-        .bind(obj.z_index)
-        .bind(obj.rotation)
+        .bind(&ban.user_id)
+        .bind(&ban.banned_by)
+        .bind(&ban.reason)
+        .bind(ban.expires_at)
+        .bind(ban.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn delete_object(&self, object_id: &str) -> Result<(), sqlx::Error> {
-        sqlx::query("DELETE FROM room_objects WHERE id = ?")
-            .bind(object_id)
+    /// Returns whether there was a ban to lift.
+    pub async fn unban_user(&self, room_id: &str, user_id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM room_bans WHERE room_id = ? AND user_id = ?")
+            .bind(room_id)
+            .bind(user_id)
             .execute(&self.pool)
             .await?;
-        Ok(())
+        Ok(result.rows_affected() > 0)
     }
 
-    pub async fn create_messages_table(&self) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "CREATE TABLE IF NOT EXISTS messages (
-                id TEXT PRIMARY KEY,
-                room_id TEXT NOT NULL,
-                user_id TEXT NOT NULL,
-                user_name TEXT NOT NULL,
-                text TEXT NOT NULL,
-                timestamp INTEGER NOT NULL
-            )",
+    /// Bans in force at `now`; expired ones are ignored.
+    pub async fn get_bans(&self, room_id: &str, now: i64) -> Result<Vec<RoomBan>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String, Option<String>, Option<i64>, i64)>(
+            "SELECT user_id, banned_by, reason, expires_at, created_at FROM room_bans
+             WHERE room_id = ? AND (expires_at IS NULL OR expires_at > ?) ORDER BY created_at DESC"
         )
-        .execute(&self.pool)
+        .bind(room_id)
+        .bind(now)
+        .fetch_all(&self.pool)
         .await?;
-        Ok(())
+
+        Ok(rows.into_iter().map(|(user_id, banned_by, reason, expires_at, created_at)| RoomBan {
+            user_id,
+            banned_by,
+            reason,
+            expires_at,
+            created_at,
+        }).collect())
     }
 
-    pub async fn save_message(&self, msg: &crate::types::ChatMessage, room_id: &str) -> Result<(), sqlx::Error> {
-        sqlx::query(
-            "INSERT INTO messages (id, room_id, user_id, user_name, text, timestamp) VALUES (?, ?, ?, ?, ?, ?)"
+    pub async fn find_ban(&self, room_id: &str, user_id: &str, now: i64) -> Result<Option<RoomBan>, sqlx::Error> {
+        let row = sqlx::query_as::<_, (String, String, Option<String>, Option<i64>, i64)>(
+            "SELECT user_id, banned_by, reason, expires_at, created_at FROM room_bans
+             WHERE room_id = ? AND user_id = ? AND (expires_at IS NULL OR expires_at > ?)"
         )
-        .bind(&msg.id)
         .bind(room_id)
-        .bind(&msg.user_id)
-        .bind(&msg.user_name)
-        .bind(&msg.text)
-        .bind(msg.timestamp)
+        .bind(user_id)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(user_id, banned_by, reason, expires_at, created_at)| RoomBan {
+            user_id,
+            banned_by,
+            reason,
+            expires_at,
+            created_at,
+        }))
+    }
+
+    /// The rooms `user_id` is currently banned from.
+    pub async fn get_banned_room_ids(&self, user_id: &str, now: i64) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT room_id FROM room_bans WHERE user_id = ? AND (expires_at IS NULL OR expires_at > ?)"
+        )
+        .bind(user_id)
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await
+    }
+
+    pub async fn create_report(&self, report: &Report) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO reports (id, room_id, reporter_id, target_id, reason, status, created_at) VALUES (?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&report.id)
+        .bind(&report.room_id)
+        .bind(&report.reporter_id)
+        .bind(&report.target_id)
+        .bind(&report.reason)
+        .bind(&report.status)
+        .bind(report.created_at)
         .execute(&self.pool)
         .await?;
         Ok(())
     }
 
-    pub async fn get_messages(&self, room_id: &str) -> Result<Vec<crate::types::ChatMessage>, sqlx::Error> {
-        let rows = sqlx::query_as::<_, (String, String, String, String, i64)>(
-            "SELECT id, user_id, user_name, text, timestamp FROM messages WHERE room_id = ? ORDER BY timestamp ASC"
+    /// Reports with the given status (all of them without one), oldest first.
+    pub async fn get_reports(&self, status: Option<&str>) -> Result<Vec<Report>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String, String, String, String, String, i64, Option<String>, Option<i64>)>(
+            "SELECT id, room_id, reporter_id, target_id, reason, status, created_at, resolved_by, resolved_at
+             FROM reports WHERE ? IS NULL OR status = ? ORDER BY created_at ASC"
         )
-        .bind(room_id)
+        .bind(status)
+        .bind(status)
         .fetch_all(&self.pool)
         .await?;
 
-        Ok(rows.into_iter().map(|(id, user_id, user_name, text, timestamp)| crate::types::ChatMessage {
+        Ok(rows.into_iter().map(|(id, room_id, reporter_id, target_id, reason, status, created_at, resolved_by, resolved_at)| Report {
             id,
-            user_id,
-            user_name,
-            text,
-            timestamp,
+            room_id,
+            reporter_id,
+            target_id,
+            reason,
+            status,
+            created_at,
+            resolved_by,
+            resolved_at,
         }).collect())
     }
+
+    /// Returns whether an open report was resolved.
+    pub async fn resolve_report(&self, id: &str, resolved_by: &str, now: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE reports SET status = 'resolved', resolved_by = ?, resolved_at = ? WHERE id = ? AND status = 'open'"
+        )
+        .bind(resolved_by)
+        .bind(now)
+        .bind(id)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn room_exists(&self, room_id: &str) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query_as::<_, (i64,)>("SELECT COUNT(*) FROM rooms WHERE id = ?")
+            .bind(room_id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.0 > 0)
+    }
+
+    /// Saves a room's name and access settings, leaving its contents alone.
+    pub async fn update_room(&self, room: &Room) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE rooms SET name = ?, visibility = ?, password_hash = ?, description = ?, category = ? WHERE id = ?"
+        )
+        .bind(&room.name)
+        .bind(room.visibility.as_str())
+        .bind(&room.password_hash)
+        .bind(&room.description)
+        .bind(&room.category)
+        .bind(&room.id)
+        .execute(&self.pool)
+        .await?;
+        self.set_room_tags(&room.id, &room.tags).await
+    }
+
+    /// Tags of every room, by room id.
+    pub async fn get_room_tags(&self) -> Result<HashMap<String, Vec<String>>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String)>("SELECT room_id, tag FROM room_tags ORDER BY tag")
+            .fetch_all(&self.pool)
+            .await?;
+        let mut tags: HashMap<String, Vec<String>> = HashMap::new();
+        for (room_id, tag) in rows {
+            tags.entry(room_id).or_default().push(tag);
+        }
+        Ok(tags)
+    }
+
+    pub async fn set_room_tags(&self, room_id: &str, tags: &[String]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        write_room_tags(&mut tx, room_id, tags).await?;
+        tx.commit().await
+    }
+
+    /// Public rooms for the room directory: id, created at and last active at.
+    pub async fn get_room_activity(&self) -> Result<HashMap<String, (Option<i64>, Option<i64>)>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, Option<i64>, Option<i64>)>(
+            "SELECT id, created_at, last_active_at FROM rooms WHERE visibility = 'public'"
+        )
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|(id, created, active)| (id, (created, active))).collect())
+    }
+
+    pub async fn touch_room(&self, room_id: &str, now: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE rooms SET last_active_at = ? WHERE id = ?")
+            .bind(now)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes a room with its objects, settings and chat history.
+    pub async fn delete_room(&self, room_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        for query in [
+            "DELETE FROM room_objects WHERE room_id = ?",
+            "DELETE FROM room_settings WHERE room_id = ?",
+            "DELETE FROM room_roles WHERE room_id = ?",
+            "DELETE FROM room_bans WHERE room_id = ?",
+            "DELETE FROM room_mutes WHERE room_id = ?",
+            "DELETE FROM room_snapshots WHERE room_id = ?",
+            "DELETE FROM room_tags WHERE room_id = ?",
+            "DELETE FROM message_reactions WHERE message_id IN (SELECT id FROM messages WHERE room_id = ?)",
+            "DELETE FROM messages WHERE room_id = ?",
+            "DELETE FROM rooms WHERE id = ?",
+        ] {
+            sqlx::query(query).bind(room_id).execute(&mut *tx).await?;
+        }
+        tx.commit().await
+    }
+
+    /// Writes the settings kept in `room_settings` for a whole room.
+    pub async fn save_room_settings(&self, room: &Room) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        write_room_settings(&mut tx, room).await?;
+        tx.commit().await
+    }
+
+    pub async fn save_template(&self, template: &RoomTemplate) -> Result<(), sqlx::Error> {
+        let objects = serde_json::to_string(&template.objects).unwrap_or_else(|_| "[]".to_string());
+        sqlx::query(
+            "INSERT INTO room_templates (id, name, owner_id, background, max_users, knock, objects, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&template.id)
+        .bind(&template.name)
+        .bind(&template.owner_id)
+        .bind(&template.background)
+        .bind(template.max_users.map(|m| m as i64))
+        .bind(template.knock)
+        .bind(objects)
+        .bind(template.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Templates of an account, newest first.
+    pub async fn get_templates(&self, owner_id: &str) -> Result<Vec<RoomTemplate>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, TemplateRow>(
+            "SELECT id, name, owner_id, background, max_users, knock, objects, created_at
+             FROM room_templates WHERE owner_id = ? ORDER BY created_at DESC"
+        )
+        .bind(owner_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(template_from_row).collect())
+    }
+
+    pub async fn get_template(&self, id: &str) -> Result<Option<RoomTemplate>, sqlx::Error> {
+        let row = sqlx::query_as::<_, TemplateRow>(
+            "SELECT id, name, owner_id, background, max_users, knock, objects, created_at
+             FROM room_templates WHERE id = ?"
+        )
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(template_from_row))
+    }
+
+    pub async fn delete_template(&self, id: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("DELETE FROM room_templates WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn save_object(&self, room_id: &str, obj: &RoomObject) -> Result<(), sqlx::Error> {
+        upsert_object(&self.pool, room_id, obj).await
+    }
+
+    /// Stores a new object. Fails with a unique violation when its id is
+    /// taken, in this room or another.
+    pub async fn insert_object(&self, room_id: &str, obj: &RoomObject) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO room_objects (id, room_id, type, x, y, width, height, content, z_index, rotation)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&obj.id)
+        .bind(room_id)
+        .bind(&obj.obj_type)
+        .bind(obj.x)
+        .bind(obj.y)
+        .bind(obj.width)
+        .bind(obj.height)
+        .bind(&obj.content)
+        .bind(obj.z_index)
+        .bind(obj.rotation)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Swaps all of a room's objects for `objects` in one go.
+    pub async fn replace_room_objects(&self, room_id: &str, objects: &[RoomObject]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("DELETE FROM room_objects WHERE room_id = ?")
+            .bind(room_id)
+            .execute(&mut *tx)
+            .await?;
+        for obj in objects {
+            upsert_object(&mut *tx, room_id, obj).await?;
+        }
+        tx.commit().await
+    }
+
+    pub async fn save_snapshot(&self, snapshot: &RoomSnapshot) -> Result<(), sqlx::Error> {
+        let objects = serde_json::to_string(&snapshot.objects).unwrap_or_else(|_| "[]".to_string());
+        sqlx::query(
+            "INSERT INTO room_snapshots (id, room_id, label, created_by, background, max_users, knock, objects, created_at)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&snapshot.id)
+        .bind(&snapshot.room_id)
+        .bind(&snapshot.label)
+        .bind(&snapshot.created_by)
+        .bind(&snapshot.background)
+        .bind(snapshot.max_users.map(|m| m as i64))
+        .bind(snapshot.knock)
+        .bind(objects)
+        .bind(snapshot.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Snapshots of a room, newest first.
+    pub async fn get_snapshots(&self, room_id: &str) -> Result<Vec<RoomSnapshot>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, SnapshotRow>(
+            "SELECT id, room_id, label, created_by, background, max_users, knock, objects, created_at
+             FROM room_snapshots WHERE room_id = ? ORDER BY created_at DESC, rowid DESC"
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(snapshot_from_row).collect())
+    }
+
+    pub async fn latest_snapshot(&self, room_id: &str) -> Result<Option<RoomSnapshot>, sqlx::Error> {
+        let row = sqlx::query_as::<_, SnapshotRow>(
+            "SELECT id, room_id, label, created_by, background, max_users, knock, objects, created_at
+             FROM room_snapshots WHERE room_id = ? ORDER BY created_at DESC, rowid DESC LIMIT 1"
+        )
+        .bind(room_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(snapshot_from_row))
+    }
+
+    pub async fn get_snapshot(&self, room_id: &str, id: &str) -> Result<Option<RoomSnapshot>, sqlx::Error> {
+        let row = sqlx::query_as::<_, SnapshotRow>(
+            "SELECT id, room_id, label, created_by, background, max_users, knock, objects, created_at
+             FROM room_snapshots WHERE room_id = ? AND id = ?"
+        )
+        .bind(room_id)
+        .bind(id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(snapshot_from_row))
+    }
+
+    /// Drops all but the newest `keep` automatic snapshots of a room.
+    pub async fn prune_auto_snapshots(&self, room_id: &str, keep: i64) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "DELETE FROM room_snapshots WHERE room_id = ? AND created_by IS NULL AND id NOT IN (
+                SELECT id FROM room_snapshots WHERE room_id = ? AND created_by IS NULL
+                ORDER BY created_at DESC, rowid DESC LIMIT ?
+            )"
+        )
+        .bind(room_id)
+        .bind(room_id)
+        .bind(keep)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn delete_object(&self, room_id: &str, object_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("DELETE FROM room_objects WHERE id = ? AND room_id = ?")
+            .bind(object_id)
+            .bind(room_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn save_message(&self, msg: &crate::types::ChatMessage, room_id: &str) -> Result<(), sqlx::Error> {
+        insert_message(&self.pool, msg, room_id).await
+    }
+
+    pub async fn get_messages(&self, room_id: &str) -> Result<Vec<crate::types::ChatMessage>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, MessageRow>(
+            "SELECT id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id,
+                    (SELECT COUNT(*) FROM messages r WHERE r.parent_id = m.id AND r.deleted_at IS NULL)
+             FROM messages m WHERE room_id = ? ORDER BY timestamp ASC"
+        )
+        .bind(room_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().map(message_from_row).collect())
+    }
+
+    /// Up to `limit` of the latest messages sent before `before` (or at all),
+    /// oldest first, with their reactions. Replies are left to their threads.
+    /// Pages go by timestamp and then id, so messages sent in the same
+    /// millisecond aren't lost at a page boundary: with `before_id` the page
+    /// starts right before that message, without it before the timestamp.
+    pub async fn get_messages_before(&self, room_id: &str, before: Option<i64>, before_id: Option<&str>, limit: i64) -> Result<Vec<crate::types::ChatMessage>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, MessageRow>(
+            "SELECT id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id,
+                    (SELECT COUNT(*) FROM messages r WHERE r.parent_id = m.id AND r.deleted_at IS NULL)
+             FROM messages m
+             WHERE room_id = ?1 AND parent_id IS NULL
+               AND (?2 IS NULL OR timestamp < ?2 OR (timestamp = ?2 AND id < ?3))
+             ORDER BY timestamp DESC, id DESC LIMIT ?4"
+        )
+        .bind(room_id)
+        .bind(before)
+        .bind(before_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages: Vec<_> = rows.into_iter().rev().map(message_from_row).collect();
+        self.attach_reactions(&mut messages).await?;
+        Ok(messages)
+    }
+
+    /// Up to `limit` of the latest replies to a message sent before `before`
+    /// (or at all), oldest first, with their reactions. Paged like
+    /// `get_messages_before`.
+    pub async fn get_replies_before(&self, parent_id: &str, before: Option<i64>, before_id: Option<&str>, limit: i64) -> Result<Vec<crate::types::ChatMessage>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, MessageRow>(
+            "SELECT id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id, 0
+             FROM messages
+             WHERE parent_id = ?1 AND (?2 IS NULL OR timestamp < ?2 OR (timestamp = ?2 AND id < ?3))
+             ORDER BY timestamp DESC, id DESC LIMIT ?4"
+        )
+        .bind(parent_id)
+        .bind(before)
+        .bind(before_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages: Vec<_> = rows.into_iter().rev().map(message_from_row).collect();
+        self.attach_reactions(&mut messages).await?;
+        Ok(messages)
+    }
+
+    async fn attach_reactions(&self, messages: &mut [crate::types::ChatMessage]) -> Result<(), sqlx::Error> {
+        let ids: Vec<&str> = messages.iter().filter(|m| m.deleted_at.is_none()).map(|m| m.id.as_str()).collect();
+        if ids.is_empty() {
+            return Ok(());
+        }
+        let rows = sqlx::query_as::<_, (String, String, String)>(
+            "SELECT message_id, emoji, user_id FROM message_reactions
+             WHERE message_id IN (SELECT value FROM json_each(?))
+             ORDER BY created_at ASC"
+        )
+        .bind(serde_json::to_string(&ids).unwrap_or_else(|_| "[]".to_string()))
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut reactions: HashMap<String, Vec<Reaction>> = HashMap::new();
+        for (message_id, emoji, user_id) in rows {
+            let list = reactions.entry(message_id).or_default();
+            match list.iter_mut().find(|r| r.emoji == emoji) {
+                Some(reaction) => {
+                    reaction.count += 1;
+                    reaction.user_ids.push(user_id);
+                }
+                None => list.push(Reaction { emoji, count: 1, user_ids: vec![user_id] }),
+            }
+        }
+        for message in messages {
+            message.reactions = reactions.remove(&message.id).unwrap_or_default();
+        }
+        Ok(())
+    }
+
+    /// A message and the room it was sent in.
+    pub async fn get_message(&self, id: &str) -> Result<Option<(String, crate::types::ChatMessage)>, sqlx::Error> {
+        let Some(room_id) = sqlx::query_scalar::<_, String>("SELECT room_id FROM messages WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+        else {
+            return Ok(None);
+        };
+        let row = sqlx::query_as::<_, MessageRow>(
+            "SELECT id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id,
+                    (SELECT COUNT(*) FROM messages r WHERE r.parent_id = m.id AND r.deleted_at IS NULL)
+             FROM messages m WHERE id = ?"
+        )
+        .bind(id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        let mut messages = vec![message_from_row(row)];
+        self.attach_reactions(&mut messages).await?;
+        Ok(messages.pop().map(|message| (room_id, message)))
+    }
+
+    /// Changes the text of a message that hasn't been deleted.
+    pub async fn edit_message(&self, id: &str, text: &str, edited_at: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE messages SET text = ?, edited_at = ? WHERE id = ? AND deleted_at IS NULL")
+            .bind(text)
+            .bind(edited_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn delete_message(&self, id: &str, deleted_at: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query("UPDATE messages SET deleted_at = ? WHERE id = ? AND deleted_at IS NULL")
+            .bind(deleted_at)
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    /// Messages of `room_ids` matching the full-text query, best matches
+    /// first, each with a snippet of its text around the matches.
+    pub async fn search_messages(&self, search: &MessageSearch<'_>) -> Result<Vec<(String, crate::types::ChatMessage, String)>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, SearchRow>(
+            "SELECT m.id, m.user_id, m.user_name, m.text, m.timestamp, m.account_id, m.edited_at, m.deleted_at, m.parent_id,
+                    (SELECT COUNT(*) FROM messages r WHERE r.parent_id = m.id AND r.deleted_at IS NULL),
+                    m.room_id, snippet(messages_fts, 0, ?1, ?2, '…', 16)
+             FROM messages_fts JOIN messages m ON m.search_id = messages_fts.rowid
+             WHERE messages_fts MATCH ?3
+               AND m.deleted_at IS NULL
+               AND m.room_id IN (SELECT value FROM json_each(?4))
+               AND (?5 IS NULL OR m.user_id = ?5 OR m.account_id = ?5)
+               AND (?6 IS NULL OR m.timestamp >= ?6)
+               AND (?7 IS NULL OR m.timestamp < ?7)
+             ORDER BY CASE WHEN ?10 THEN m.timestamp END DESC, rank, m.timestamp DESC
+             LIMIT ?8 OFFSET ?9"
+        )
+        .bind(SNIPPET_START)
+        .bind(SNIPPET_END)
+        .bind(search.query)
+        .bind(serde_json::to_string(search.room_ids).unwrap_or_else(|_| "[]".to_string()))
+        .bind(search.author)
+        .bind(search.from)
+        .bind(search.to)
+        .bind(search.limit)
+        .bind(search.offset)
+        .bind(search.newest_first)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut found = Vec::with_capacity(rows.len());
+        let mut messages = Vec::with_capacity(rows.len());
+        for (id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id, reply_count, room_id, snippet) in rows {
+            messages.push(message_from_row((id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id, reply_count)));
+            found.push((room_id, snippet));
+        }
+        self.attach_reactions(&mut messages).await?;
+        Ok(found.into_iter().zip(messages).map(|((room_id, snippet), message)| (room_id, message, snippet)).collect())
+    }
+
+    /// Adds the user's reaction to a message, or takes it away if they had
+    /// already reacted with that emoji. Returns whether it was added.
+    pub async fn toggle_reaction(&self, message_id: &str, user_id: &str, emoji: &str, now: i64) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let removed = sqlx::query("DELETE FROM message_reactions WHERE message_id = ? AND emoji = ? AND user_id = ?")
+            .bind(message_id)
+            .bind(emoji)
+            .bind(user_id)
+            .execute(&mut *tx)
+            .await?;
+        if removed.rows_affected() == 0 {
+            sqlx::query("INSERT INTO message_reactions (message_id, user_id, emoji, created_at) VALUES (?, ?, ?, ?)")
+                .bind(message_id)
+                .bind(user_id)
+                .bind(emoji)
+                .bind(now)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await?;
+        Ok(removed.rows_affected() == 0)
+    }
+
+    /// Who reacted to a message with `emoji`, first reaction first.
+    pub async fn get_reaction_users(&self, message_id: &str, emoji: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT user_id FROM message_reactions WHERE message_id = ? AND emoji = ? ORDER BY created_at ASC")
+            .bind(message_id)
+            .bind(emoji)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// The one-to-one conversation of the pair of accounts in `pair_key`.
+    pub async fn find_pair_conversation(&self, pair_key: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT id FROM dm_conversations WHERE pair_key = ?")
+            .bind(pair_key)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn create_conversation(
+        &self,
+        id: &str,
+        name: Option<&str>,
+        pair_key: Option<&str>,
+        created_by: &str,
+        members: &[String],
+        created_at: i64,
+    ) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("INSERT INTO dm_conversations (id, name, pair_key, created_by, created_at) VALUES (?, ?, ?, ?, ?)")
+            .bind(id)
+            .bind(name)
+            .bind(pair_key)
+            .bind(created_by)
+            .bind(created_at)
+            .execute(&mut *tx)
+            .await?;
+        for account_id in members {
+            sqlx::query("INSERT INTO dm_members (conversation_id, account_id) VALUES (?, ?)")
+                .bind(id)
+                .bind(account_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        tx.commit().await
+    }
+
+    /// The account's conversations, most recently active first, or just
+    /// `only` if the account is in it.
+    pub async fn get_conversations(&self, account_id: &str, only: Option<&str>) -> Result<Vec<DmConversation>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, Option<String>, i64, Option<i64>, i64)>(
+            "SELECT c.id, c.name, c.created_at, c.last_message_at,
+                    (SELECT COUNT(*) FROM dm_messages d WHERE d.conversation_id = c.id
+                     AND d.sender_id != m.account_id AND d.timestamp > m.last_read_at)
+             FROM dm_conversations c JOIN dm_members m ON m.conversation_id = c.id
+             WHERE m.account_id = ? AND (? IS NULL OR c.id = ?)
+             ORDER BY COALESCE(c.last_message_at, c.created_at) DESC"
+        )
+        .bind(account_id)
+        .bind(only)
+        .bind(only)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let member_rows = sqlx::query_as::<_, (String, String, String)>(
+            "SELECT m.conversation_id, m.account_id, a.username
+             FROM dm_members m JOIN accounts a ON a.id = m.account_id
+             WHERE m.conversation_id IN (SELECT conversation_id FROM dm_members WHERE account_id = ?)
+             ORDER BY a.username"
+        )
+        .bind(account_id)
+        .fetch_all(&self.pool)
+        .await?;
+        let mut members: HashMap<String, Vec<DmMember>> = HashMap::new();
+        for (conversation_id, account_id, username) in member_rows {
+            members.entry(conversation_id).or_default().push(DmMember { account_id, username });
+        }
+
+        Ok(rows.into_iter().map(|(id, name, created_at, last_message_at, unread_count)| DmConversation {
+            members: members.remove(&id).unwrap_or_default(),
+            id,
+            name,
+            created_at,
+            last_message_at,
+            unread_count,
+        }).collect())
+    }
+
+    /// Saves a direct message. Sending one marks the conversation as read
+    /// for the sender.
+    pub async fn save_direct_message(&self, message: &DirectMessage) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "INSERT INTO dm_messages (id, conversation_id, sender_id, sender_name, text, timestamp)
+             VALUES (?, ?, ?, ?, ?, ?)"
+        )
+        .bind(&message.id)
+        .bind(&message.conversation_id)
+        .bind(&message.sender_id)
+        .bind(&message.sender_name)
+        .bind(&message.text)
+        .bind(message.timestamp)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("UPDATE dm_conversations SET last_message_at = ? WHERE id = ?")
+            .bind(message.timestamp)
+            .bind(&message.conversation_id)
+            .execute(&mut *tx)
+            .await?;
+        sqlx::query("UPDATE dm_members SET last_read_at = ? WHERE conversation_id = ? AND account_id = ?")
+            .bind(message.timestamp)
+            .bind(&message.conversation_id)
+            .bind(&message.sender_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Up to `limit` of the latest direct messages of a conversation sent
+    /// before `before` (or at all), oldest first.
+    /// Paged like `get_messages_before`.
+    pub async fn get_direct_messages_before(&self, conversation_id: &str, before: Option<i64>, before_id: Option<&str>, limit: i64) -> Result<Vec<DirectMessage>, sqlx::Error> {
+        let rows = sqlx::query_as::<_, (String, String, String, String, String, i64)>(
+            "SELECT id, conversation_id, sender_id, sender_name, text, timestamp FROM dm_messages
+             WHERE conversation_id = ?1 AND (?2 IS NULL OR timestamp < ?2 OR (timestamp = ?2 AND id < ?3))
+             ORDER BY timestamp DESC, id DESC LIMIT ?4"
+        )
+        .bind(conversation_id)
+        .bind(before)
+        .bind(before_id)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows.into_iter().rev().map(|(id, conversation_id, sender_id, sender_name, text, timestamp)| DirectMessage {
+            id,
+            conversation_id,
+            sender_id,
+            sender_name,
+            text,
+            timestamp,
+        }).collect())
+    }
+
+    pub async fn mark_conversation_read(&self, conversation_id: &str, account_id: &str, read_at: i64) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE dm_members SET last_read_at = MAX(last_read_at, ?) WHERE conversation_id = ? AND account_id = ?")
+            .bind(read_at)
+            .bind(conversation_id)
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Direct messages sent to the account that it hasn't read yet, across
+    /// all of its conversations.
+    pub async fn count_unread_direct_messages(&self, account_id: &str) -> Result<i64, sqlx::Error> {
+        sqlx::query_scalar(
+            "SELECT COUNT(*) FROM dm_messages d JOIN dm_members m ON m.conversation_id = d.conversation_id
+             WHERE m.account_id = ? AND d.sender_id != m.account_id AND d.timestamp > m.last_read_at"
+        )
+        .bind(account_id)
+        .fetch_one(&self.pool)
+        .await
+    }
+
+    // A refresh token row is a login session: access tokens carry its id as `sid`
+    // and stop working as soon as it is revoked.
+    pub async fn create_session(
+        &self,
+        id: &str,
+        account_id: &str,
+        token_hash: &str,
+        expires_at: i64,
+        authenticated_at: Option<i64>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO refresh_tokens (id, account_id, token_hash, expires_at, authenticated_at) VALUES (?, ?, ?, ?, ?)"
+        )
+        .bind(id)
+        .bind(account_id)
+        .bind(token_hash)
+        .bind(expires_at)
+        .bind(authenticated_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Returns `(session id, account id, username)` for a live refresh token.
+    pub async fn find_session_by_refresh(&self, token_hash: &str, now: i64) -> Result<Option<(String, String, String)>, sqlx::Error> {
+        sqlx::query_as(
+            "SELECT r.id, r.account_id, a.username FROM refresh_tokens r
+             JOIN accounts a ON a.id = r.account_id
+             WHERE r.token_hash = ? AND r.revoked = 0 AND r.expires_at > ?"
+        )
+        .bind(token_hash)
+        .bind(now)
+        .fetch_optional(&self.pool)
+        .await
+    }
+
+    /// Swaps in a new refresh token. Returns false if `old_hash` was already used.
+    pub async fn rotate_session(&self, id: &str, old_hash: &str, new_hash: &str, expires_at: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE refresh_tokens SET token_hash = ?, expires_at = ? WHERE id = ? AND token_hash = ? AND revoked = 0"
+        )
+        .bind(new_hash)
+        .bind(expires_at)
+        .bind(id)
+        .bind(old_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn is_session_active(&self, id: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM refresh_tokens WHERE id = ? AND revoked = 0)")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn get_session_authenticated_at(&self, id: &str) -> Result<Option<i64>, sqlx::Error> {
+        let authenticated_at: Option<Option<i64>> =
+            sqlx::query_scalar("SELECT authenticated_at FROM refresh_tokens WHERE id = ? AND revoked = 0")
+                .bind(id)
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(authenticated_at.flatten())
+    }
+
+    pub async fn revoke_session(&self, id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE refresh_tokens SET revoked = 1 WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn revoke_account_sessions(&self, account_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE refresh_tokens SET revoked = 1 WHERE account_id = ?")
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_profile(&self, account_id: &str) -> Result<Option<AccountProfile>, sqlx::Error> {
+        let row = sqlx::query_as::<_, (String, String, Option<String>, Option<String>, Option<String>)>(
+            "SELECT id, username, display_name, avatar_color, bio FROM accounts WHERE id = ?"
+        )
+        .bind(account_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|(account_id, username, display_name, avatar_color, bio)| AccountProfile {
+            account_id,
+            username,
+            display_name,
+            avatar_color,
+            bio,
+        }))
+    }
+
+    pub async fn save_profile(&self, profile: &AccountProfile) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE accounts SET display_name = ?, avatar_color = ?, bio = ? WHERE id = ?")
+            .bind(&profile.display_name)
+            .bind(&profile.avatar_color)
+            .bind(&profile.bio)
+            .bind(&profile.account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    pub async fn get_password_hash(&self, account_id: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT password_hash FROM accounts WHERE id = ?")
+            .bind(account_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn set_password_hash(&self, account_id: &str, password_hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE accounts SET password_hash = ? WHERE id = ?")
+            .bind(password_hash)
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Deletes an account together with everything written under it:
+    /// chat messages, persisted users and sessions.
+    pub async fn delete_account(&self, account_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        // ?1 is the account id throughout
+        for query in [
+            "DELETE FROM message_reactions WHERE user_id = ?1 OR user_id IN (SELECT id FROM users WHERE account_id = ?1)
+             OR message_id IN (SELECT id FROM messages WHERE account_id = ?1 OR user_id IN (SELECT id FROM users WHERE account_id = ?1))",
+            // Replies to the account's messages stay, outside of any thread
+            "UPDATE messages SET parent_id = NULL WHERE parent_id IN
+             (SELECT id FROM messages WHERE account_id = ?1 OR user_id IN (SELECT id FROM users WHERE account_id = ?1))",
+            "DELETE FROM messages WHERE account_id = ?1 OR user_id IN (SELECT id FROM users WHERE account_id = ?1)",
+            // The other members keep their conversations, without the account;
+            // conversations it was the last member of go
+            "DELETE FROM dm_messages WHERE sender_id = ?1 OR conversation_id IN (SELECT conversation_id FROM dm_members
+             WHERE account_id = ?1 AND conversation_id NOT IN (SELECT conversation_id FROM dm_members WHERE account_id != ?1))",
+            "DELETE FROM dm_conversations WHERE id IN (SELECT conversation_id FROM dm_members
+             WHERE account_id = ?1 AND conversation_id NOT IN (SELECT conversation_id FROM dm_members WHERE account_id != ?1))",
+            "DELETE FROM dm_members WHERE account_id = ?1",
+        ] {
+            sqlx::query(query).bind(account_id).execute(&mut *tx).await?;
+        }
+
+        for query in [
+            // Rooms outlive their owner, they just become unowned
+            "UPDATE rooms SET owner_id = NULL WHERE owner_id = ?",
+            "DELETE FROM room_roles WHERE account_id = ?",
+            "DELETE FROM room_templates WHERE owner_id = ?",
+            "DELETE FROM users WHERE account_id = ?",
+            "DELETE FROM refresh_tokens WHERE account_id = ?",
+            "DELETE FROM recovery_codes WHERE account_id = ?",
+            "DELETE FROM account_identities WHERE account_id = ?",
+            "DELETE FROM accounts WHERE id = ?",
+        ] {
+            sqlx::query(query).bind(account_id).execute(&mut *tx).await?;
+        }
+
+        tx.commit().await
+    }
+
+    /// Returns `(confirmed secret, pending secret, last used step)`.
+    pub async fn get_totp(&self, account_id: &str) -> Result<Option<(Option<String>, Option<String>, Option<i64>)>, sqlx::Error> {
+        sqlx::query_as("SELECT totp_secret, totp_pending_secret, totp_last_step FROM accounts WHERE id = ?")
+            .bind(account_id)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn set_pending_totp(&self, account_id: &str, secret: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE accounts SET totp_pending_secret = ? WHERE id = ?")
+            .bind(secret)
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Promotes the pending secret and replaces the recovery codes.
+    pub async fn enable_totp(&self, account_id: &str, step: i64, recovery_hashes: &[String]) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query(
+            "UPDATE accounts SET totp_secret = totp_pending_secret, totp_pending_secret = NULL, totp_last_step = ? WHERE id = ?"
+        )
+        .bind(step)
+        .bind(account_id)
+        .execute(&mut *tx)
+        .await?;
+
+        sqlx::query("DELETE FROM recovery_codes WHERE account_id = ?")
+            .bind(account_id)
+            .execute(&mut *tx)
+            .await?;
+        for code_hash in recovery_hashes {
+            sqlx::query("INSERT INTO recovery_codes (account_id, code_hash) VALUES (?, ?)")
+                .bind(account_id)
+                .bind(code_hash)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        tx.commit().await
+    }
+
+    pub async fn disable_totp(&self, account_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "UPDATE accounts SET totp_secret = NULL, totp_pending_secret = NULL, totp_last_step = NULL WHERE id = ?"
+        )
+        .bind(account_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM recovery_codes WHERE account_id = ?")
+            .bind(account_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// Records a TOTP step as used. Returns false if it (or a later one) already was,
+    /// which is how a replayed code is caught.
+    pub async fn consume_totp_step(&self, account_id: &str, step: i64) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE accounts SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)"
+        )
+        .bind(step)
+        .bind(account_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn get_unused_recovery_hashes(&self, account_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT code_hash FROM recovery_codes WHERE account_id = ? AND used = 0")
+            .bind(account_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Marks a recovery code as used. Returns false if it doesn't exist or was used before.
+    pub async fn consume_recovery_code(&self, account_id: &str, code_hash: &str) -> Result<bool, sqlx::Error> {
+        let result = sqlx::query(
+            "UPDATE recovery_codes SET used = 1 WHERE account_id = ? AND code_hash = ? AND used = 0"
+        )
+        .bind(account_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    pub async fn username_exists(&self, username: &str) -> Result<bool, sqlx::Error> {
+        sqlx::query_scalar("SELECT EXISTS(SELECT 1 FROM accounts WHERE username = ?)")
+            .bind(username)
+            .fetch_one(&self.pool)
+            .await
+    }
+
+    pub async fn create_account(&self, id: &str, username: &str, password_hash: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO accounts (id, username, password_hash) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(username)
+            .bind(password_hash)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Account linked to an external (OpenID Connect) identity.
+    pub async fn find_identity_account(&self, issuer: &str, subject: &str) -> Result<Option<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT account_id FROM account_identities WHERE issuer = ? AND subject = ?")
+            .bind(issuer)
+            .bind(subject)
+            .fetch_optional(&self.pool)
+            .await
+    }
+
+    pub async fn link_identity(&self, issuer: &str, subject: &str, account_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT INTO account_identities (issuer, subject, account_id) VALUES (?, ?, ?)")
+            .bind(issuer)
+            .bind(subject)
+            .bind(account_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+}
+
+pub fn is_unique_violation(error: &sqlx::Error) -> bool {
+    matches!(error, sqlx::Error::Database(e) if e.is_unique_violation())
+}
+
+// Only ever inserts: whoever stores a room id first keeps it
+async fn write_room(conn: &mut SqliteConnection, room: &Room) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO rooms (id, name, owner_id, visibility, password_hash, description, category, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)"
+    )
+    .bind(&room.id)
+    .bind(&room.name)
+    .bind(&room.owner_id)
+    .bind(room.visibility.as_str())
+    .bind(&room.password_hash)
+    .bind(&room.description)
+    .bind(&room.category)
+    .bind(chrono::Utc::now().timestamp())
+    .execute(&mut *conn)
+    .await?;
+    write_room_tags(conn, &room.id, &room.tags).await?;
+
+    for obj in &room.objects {
+        upsert_object(&mut *conn, &room.id, obj).await?;
+    }
+    Ok(())
+}
+
+async fn write_room_tags(conn: &mut SqliteConnection, room_id: &str, tags: &[String]) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM room_tags WHERE room_id = ?")
+        .bind(room_id)
+        .execute(&mut *conn)
+        .await?;
+    for tag in tags {
+        sqlx::query("INSERT OR IGNORE INTO room_tags (room_id, tag) VALUES (?, ?)")
+            .bind(room_id)
+            .bind(tag)
+            .execute(&mut *conn)
+            .await?;
+    }
+    Ok(())
+}
+
+async fn write_room_settings(conn: &mut SqliteConnection, room: &Room) -> Result<(), sqlx::Error> {
+    let max_users = room.max_users.map(|m| m.to_string());
+    write_room_setting(&mut *conn, &room.id, "background", room.background.as_deref()).await?;
+    write_room_setting(&mut *conn, &room.id, "max_users", max_users.as_deref()).await?;
+    write_room_setting(&mut *conn, &room.id, "knock", room.knock.then_some("true")).await
+}
+
+async fn write_room_setting<'e, E: Executor<'e, Database = Sqlite>>(executor: E, room_id: &str, key: &str, value: Option<&str>) -> Result<(), sqlx::Error> {
+    match value {
+        Some(value) => sqlx::query(
+            "INSERT INTO room_settings (room_id, key, value) VALUES (?, ?, ?)
+             ON CONFLICT(room_id, key) DO UPDATE SET value = excluded.value"
+        )
+        .bind(room_id)
+        .bind(key)
+        .bind(value),
+        None => sqlx::query("DELETE FROM room_settings WHERE room_id = ? AND key = ?")
+            .bind(room_id)
+            .bind(key),
+    }
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+async fn insert_message<'e, E: Executor<'e, Database = Sqlite>>(executor: E, msg: &crate::types::ChatMessage, room_id: &str) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "INSERT INTO messages (id, room_id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id, search_id)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, (SELECT COALESCE(MAX(search_id), 0) + 1 FROM messages))"
+    )
+    .bind(&msg.id)
+    .bind(room_id)
+    .bind(&msg.user_id)
+    .bind(&msg.user_name)
+    .bind(&msg.text)
+    .bind(msg.timestamp)
+    .bind(&msg.account_id)
+    .bind(msg.edited_at)
+    .bind(msg.deleted_at)
+    .bind(&msg.parent_id)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+/// Never touches an object of another room that happens to have the same id.
+async fn upsert_object<'e, E: Executor<'e, Database = Sqlite>>(executor: E, room_id: &str, obj: &RoomObject) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO room_objects (id, room_id, type, x, y, width, height, content, z_index, rotation)
+        VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+        ON CONFLICT(id) DO UPDATE SET
+        x = ?, y = ?, width = ?, height = ?, content = ?, z_index = ?, rotation = ?
+        WHERE room_objects.room_id = excluded.room_id
+        "#
+    )
+    .bind(&obj.id)
+    .bind(room_id)
+    .bind(&obj.obj_type)
+    .bind(obj.x)
+    .bind(obj.y)
+    .bind(obj.width)
+    .bind(obj.height)
+    .bind(&obj.content)
+    .bind(obj.z_index)
+    .bind(obj.rotation)
+    // Updates
+    .bind(obj.x)
+    .bind(obj.y)
+    .bind(obj.width)
+    .bind(obj.height)
+    .bind(&obj.content)
+    .bind(obj.z_index)
+    .bind(obj.rotation)
+    .execute(executor)
+    .await?;
+    Ok(())
+}
+
+type MessageRow = (String, String, String, String, i64, Option<String>, Option<i64>, Option<i64>, Option<String>, i64);
+
+fn message_from_row((id, user_id, user_name, text, timestamp, account_id, edited_at, deleted_at, parent_id, reply_count): MessageRow) -> crate::types::ChatMessage {
+    crate::types::ChatMessage {
+        id,
+        user_id,
+        user_name,
+        // Soft-deleted messages keep their row, but their text is never served again
+        text: if deleted_at.is_some() { String::new() } else { text },
+        timestamp,
+        account_id,
+        edited_at,
+        deleted_at,
+        parent_id,
+        reply_count,
+        reactions: Vec::new(),
+    }
+}
+
+/// What `search_messages` looks for. `from` and `to` are timestamps (ms);
+/// `author` is a user or account id.
+pub struct MessageSearch<'a> {
+    pub query: &'a str, // FTS5 query syntax
+    pub room_ids: &'a [String],
+    pub author: Option<&'a str>,
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub limit: i64,
+    pub offset: i64,
+    pub newest_first: bool, // rather than best matches first
+}
+
+type SearchRow = (String, String, String, String, i64, Option<String>, Option<i64>, Option<i64>, Option<String>, i64, String, String);
+
+/// Marks around matches in search snippets, for the caller to replace once
+/// the text is escaped.
+pub const SNIPPET_START: &str = "\u{2}";
+pub const SNIPPET_END: &str = "\u{3}";
+
+type SnapshotRow = (String, String, Option<String>, Option<String>, Option<String>, Option<i64>, bool, String, i64);
+
+fn snapshot_from_row((id, room_id, label, created_by, background, max_users, knock, objects, created_at): SnapshotRow) -> RoomSnapshot {
+    RoomSnapshot {
+        id,
+        room_id,
+        label,
+        created_by,
+        background,
+        max_users: max_users.map(|m| m as usize),
+        knock,
+        objects: serde_json::from_str(&objects).unwrap_or_default(),
+        created_at,
+    }
+}
+
+type TemplateRow = (String, String, String, Option<String>, Option<i64>, bool, String, i64);
+
+fn template_from_row((id, name, owner_id, background, max_users, knock, objects, created_at): TemplateRow) -> RoomTemplate {
+    RoomTemplate {
+        id,
+        name,
+        owner_id,
+        background,
+        max_users: max_users.map(|m| m as usize),
+        knock,
+        objects: serde_json::from_str(&objects).unwrap_or_default(),
+        created_at,
+    }
+}
+
+fn setting<'a>(settings: &'a [(String, String)], key: &str) -> Option<&'a str> {
+    settings.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+}
+
+// Databases created by older builds keep their original schema, since
+// CREATE TABLE IF NOT EXISTS won't touch them. Columns added later go through here.
+/// Returns whether the column had to be added.
+async fn add_column_if_missing(pool: &SqlitePool, table: &str, column: &str, decl: &str) -> Result<bool, sqlx::Error> {
+    let exists: bool = sqlx::query_scalar(&format!(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info('{}') WHERE name = ?)",
+        table
+    ))
+    .bind(column)
+    .fetch_one(pool)
+    .await?;
+
+    if !exists {
+        sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))
+            .execute(pool)
+            .await?;
+    }
+    Ok(!exists)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(id: &str, x: f64) -> RoomObject {
+        RoomObject {
+            id: id.to_string(),
+            obj_type: "note".to_string(),
+            x,
+            y: 0.0,
+            width: 100.0,
+            height: 100.0,
+            content: "hello".to_string(),
+            z_index: 0,
+            rotation: 0.0,
+        }
+    }
+
+    fn message(id: &str, text: &str, timestamp: i64) -> crate::types::ChatMessage {
+        crate::types::ChatMessage {
+            id: id.to_string(),
+            user_id: "user".to_string(),
+            user_name: "User".to_string(),
+            text: text.to_string(),
+            timestamp,
+            account_id: None,
+            edited_at: None,
+            deleted_at: None,
+            parent_id: None,
+            reply_count: 0,
+            reactions: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn history_pages_keep_messages_sharing_a_millisecond() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_message(&message("a", "older", 1), "room").await.unwrap();
+        for id in ["b", "c", "d", "e"] {
+            db.save_message(&message(id, "same time", 2), "room").await.unwrap();
+        }
+
+        let mut seen = Vec::new();
+        let mut before: Option<(i64, String)> = None;
+        loop {
+            let page = db.get_messages_before(
+                "room",
+                before.as_ref().map(|(t, _)| *t),
+                before.as_ref().map(|(_, id)| id.as_str()),
+                2,
+            ).await.unwrap();
+            let Some(oldest) = page.first() else { break };
+            before = Some((oldest.timestamp, oldest.id.clone()));
+            seen.splice(0..0, page.into_iter().map(|m| m.id));
+        }
+        assert_eq!(seen, ["a", "b", "c", "d", "e"]);
+    }
+
+    #[tokio::test]
+    async fn search_survives_renumbered_rowids() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        for (i, text) in ["first apple", "second banana", "third cherry"].iter().enumerate() {
+            db.save_message(&message(&format!("m{}", i), text, i as i64), "room").await.unwrap();
+        }
+        // What VACUUM is allowed to do to a table with a TEXT primary key
+        sqlx::query("DELETE FROM messages WHERE id = 'm0'").execute(&db.pool).await.unwrap();
+        sqlx::query("UPDATE messages SET rowid = rowid - 1").execute(&db.pool).await.unwrap();
+
+        let rooms = vec!["room".to_string()];
+        let found = db.search_messages(&MessageSearch {
+            query: "cherry",
+            room_ids: &rooms,
+            author: None,
+            from: None,
+            to: None,
+            limit: 10,
+            offset: 0,
+            newest_first: false,
+        }).await.unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].1.id, "m2");
+    }
+
+    #[tokio::test]
+    async fn saving_an_object_never_touches_another_rooms() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_object("room-a", &note("shared", 1.0)).await.unwrap();
+        db.save_object("room-b", &note("shared", 2.0)).await.unwrap();
+        db.delete_object("room-b", "shared").await.unwrap();
+
+        let objects = db.get_room_objects("room-a").await.unwrap();
+        assert_eq!(objects.len(), 1);
+        assert_eq!(objects[0].x, 1.0);
+        assert!(db.get_room_objects("room-b").await.unwrap().is_empty());
+    }
 }
@@ -0,0 +1,234 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use socketioxide::{operators::BroadcastOperators, SocketIo};
+use crate::auth::AuthUser;
+use crate::state::AppState;
+use crate::types::{DirectMessage, DmConversation};
+
+// Members of a group conversation, its creator included
+const MAX_GROUP_MEMBERS: usize = 10;
+const MAX_MESSAGE_CHARS: usize = 4_000;
+const PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 100;
+
+#[derive(Deserialize)]
+pub struct CreateConversation {
+    #[serde(rename = "accountIds")]
+    account_ids: Vec<String>, // everyone but the caller
+    name: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct SendMessage {
+    text: String,
+}
+
+#[derive(Deserialize)]
+pub struct MessagesQuery {
+    before: Option<i64>, // timestamp (ms) of the oldest message already loaded
+    #[serde(rename = "beforeId")]
+    before_id: Option<String>, // and its id, for messages sent in the same millisecond
+    limit: Option<usize>,
+}
+
+/// The conversation, if the account is one of its members.
+async fn member_conversation(state: &AppState, conversation_id: &str, account_id: &str) -> Result<DmConversation, (StatusCode, &'static str)> {
+    match state.db.get_conversations(account_id, Some(conversation_id)).await {
+        Ok(mut conversations) if !conversations.is_empty() => Ok(conversations.remove(0)),
+        Ok(_) => Err((StatusCode::NOT_FOUND, "Conversation not found")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load conversation")),
+    }
+}
+
+/// Sends a direct message and delivers it to every socket of every member,
+/// whichever room they are in.
+pub async fn send(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    conversation_id: &str,
+    account_id: &str,
+    text: &str,
+) -> Result<DirectMessage, (StatusCode, &'static str)> {
+    let text = text.trim();
+    if text.is_empty() || text.chars().count() > MAX_MESSAGE_CHARS {
+        return Err((StatusCode::BAD_REQUEST, "Message must be 1 to 4000 characters"));
+    }
+    let conversation = member_conversation(state, conversation_id, account_id).await?;
+    let sender_name = match state.db.get_profile(account_id).await {
+        Ok(Some(profile)) => profile.display_name.unwrap_or(profile.username),
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Account not found")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to send message")),
+    };
+
+    let message = DirectMessage {
+        id: uuid::Uuid::new_v4().to_string(),
+        conversation_id: conversation.id,
+        sender_id: account_id.to_string(),
+        sender_name,
+        text: text.to_string(),
+        timestamp: chrono::Utc::now().timestamp_millis(),
+    };
+    if state.db.save_direct_message(&message).await.is_err() {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to send message"));
+    }
+    for member in &conversation.members {
+        let _ = within(format!("account:{}", member.account_id)).emit("dm_message", &message);
+    }
+    Ok(message)
+}
+
+/// Marks the conversation as read up to now, and tells the account's other
+/// sockets so they can clear their unread counts.
+pub async fn mark_read(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    conversation_id: &str,
+    account_id: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    member_conversation(state, conversation_id, account_id).await?;
+    let now = chrono::Utc::now().timestamp_millis();
+    if state.db.mark_conversation_read(conversation_id, account_id, now).await.is_err() {
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to mark conversation as read"));
+    }
+    let _ = within(format!("account:{}", account_id))
+        .emit("dm_read", json!({ "conversationId": conversation_id, "readAt": now }));
+    Ok(())
+}
+
+/// The caller's conversations, most recently active first, with their
+/// unread counts.
+pub async fn list(State(state): State<AppState>, user: AuthUser) -> impl IntoResponse {
+    match state.db.get_conversations(&user.account_id, None).await {
+        Ok(conversations) => Json(conversations).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load conversations").into_response(),
+    }
+}
+
+/// Starts a conversation between the caller and `accountIds`. There is only
+/// one one-to-one conversation per pair of accounts: asking for it again
+/// returns the existing one.
+pub async fn create(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Json(payload): Json<CreateConversation>,
+) -> impl IntoResponse {
+    let mut members: Vec<String> = payload.account_ids.into_iter()
+        .filter(|id| *id != user.account_id)
+        .collect();
+    members.sort();
+    members.dedup();
+    if members.is_empty() {
+        return (StatusCode::BAD_REQUEST, "A conversation needs someone else in it").into_response();
+    }
+    if members.len() + 1 > MAX_GROUP_MEMBERS {
+        return (StatusCode::BAD_REQUEST, "Too many members").into_response();
+    }
+    let name = payload.name.map(|n| n.trim().to_string()).filter(|n| !n.is_empty());
+    if name.as_ref().is_some_and(|n| n.chars().count() > 64) {
+        return (StatusCode::BAD_REQUEST, "Conversation name must be at most 64 characters").into_response();
+    }
+    for account_id in &members {
+        match state.db.get_profile(account_id).await {
+            Ok(Some(_)) => {}
+            Ok(None) => return (StatusCode::NOT_FOUND, "Account not found").into_response(),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create conversation").into_response(),
+        }
+    }
+    members.push(user.account_id.clone());
+
+    // One-to-one conversations are never named
+    let pair_key = match (members.len(), &name) {
+        (2, None) => {
+            let mut pair = members.clone();
+            pair.sort();
+            Some(pair.join(":"))
+        }
+        _ => None,
+    };
+    if let Some(pair_key) = &pair_key {
+        match state.db.find_pair_conversation(pair_key).await {
+            Ok(Some(id)) => return match member_conversation(&state, &id, &user.account_id).await {
+                Ok(conversation) => Json(conversation).into_response(),
+                Err(error) => error.into_response(),
+            },
+            Ok(None) => {}
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create conversation").into_response(),
+        }
+    }
+
+    let id = uuid::Uuid::new_v4().to_string();
+    let now = chrono::Utc::now().timestamp_millis();
+    if state.db.create_conversation(&id, name.as_deref(), pair_key.as_deref(), &user.account_id, &members, now).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to create conversation").into_response();
+    }
+    let conversation = match member_conversation(&state, &id, &user.account_id).await {
+        Ok(conversation) => conversation,
+        Err(error) => return error.into_response(),
+    };
+    for account_id in &members {
+        let _ = io.within(format!("account:{}", account_id)).emit("dm_conversation_created", &conversation);
+    }
+    (StatusCode::CREATED, Json(conversation)).into_response()
+}
+
+/// A page of a conversation's messages, oldest first, as
+/// `{ conversationId, before, beforeId, messages, hasMore }`.
+pub async fn messages(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(conversation_id): Path<String>,
+    Query(query): Query<MessagesQuery>,
+) -> impl IntoResponse {
+    if let Err(error) = member_conversation(&state, &conversation_id, &user.account_id).await {
+        return error.into_response();
+    }
+
+    let limit = query.limit.unwrap_or(PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let mut messages = match state.db.get_direct_messages_before(&conversation_id, query.before, query.before_id.as_deref(), limit as i64 + 1).await {
+        Ok(messages) => messages,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load messages").into_response(),
+    };
+    let has_more = messages.len() > limit;
+    if has_more {
+        messages.remove(0);
+    }
+    Json(json!({
+        "conversationId": conversation_id,
+        "before": query.before,
+        "beforeId": query.before_id,
+        "messages": messages,
+        "hasMore": has_more,
+    })).into_response()
+}
+
+pub async fn send_message(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(conversation_id): Path<String>,
+    Json(payload): Json<SendMessage>,
+) -> impl IntoResponse {
+    match send(&state, |room: String| io.within(room), &conversation_id, &user.account_id, &payload.text).await {
+        Ok(message) => (StatusCode::CREATED, Json(message)).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+pub async fn read(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(conversation_id): Path<String>,
+) -> impl IntoResponse {
+    match mark_read(&state, |room: String| io.within(room), &conversation_id, &user.account_id).await {
+        Ok(()) => (StatusCode::OK, "Conversation marked as read").into_response(),
+        Err(error) => error.into_response(),
+    }
+}
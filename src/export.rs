@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use socketioxide::SocketIo;
+use crate::api::{self, CreateRoom};
+use crate::auth::AuthUser;
+use crate::db;
+use crate::roles::Permission;
+use crate::state::AppState;
+use crate::templates;
+use crate::types::{ChatMessage, RoomObject, Visibility};
+
+const EXPORT_VERSION: u32 = 1;
+const MAX_IMPORT_OBJECTS: usize = 1_000;
+const MAX_IMPORT_MESSAGES: usize = 10_000;
+// Exports with chat history can be a lot bigger than other request bodies
+pub const MAX_IMPORT_BYTES: usize = 16 * 1024 * 1024;
+
+/// A room as written by the export endpoint. Bump `EXPORT_VERSION` whenever
+/// this changes shape.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomExport {
+    version: u32,
+    exported_at: i64,
+    room: ExportedRoom,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    messages: Option<Vec<ChatMessage>>,
+}
+
+// Passwords, roles and bans belong to accounts of the deployment the room
+// came from, so they stay behind
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ExportedRoom {
+    id: String,
+    name: String,
+    #[serde(default)]
+    visibility: Visibility,
+    #[serde(default)]
+    description: Option<String>,
+    #[serde(default)]
+    category: Option<String>,
+    #[serde(default)]
+    tags: Vec<String>,
+    #[serde(default)]
+    background: Option<String>,
+    #[serde(default)]
+    max_users: Option<usize>,
+    #[serde(default)]
+    knock: bool,
+    #[serde(default)]
+    objects: Vec<RoomObject>,
+}
+
+#[derive(Deserialize)]
+pub struct ExportQuery {
+    #[serde(default)]
+    messages: bool,
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    id: Option<String>, // instead of the exported room's id, e.g. when it's taken
+    name: Option<String>,
+}
+
+/// The room's objects and settings as a versioned JSON document, with its
+/// chat history when asked for with `?messages=true`.
+pub async fn export_room(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    Query(query): Query<ExportQuery>,
+) -> impl IntoResponse {
+    let Some(room) = state.get_room(&room_id) else {
+        return (StatusCode::NOT_FOUND, "Room not found").into_response();
+    };
+    if !room.role_of(Some(&user.account_id)).can(Permission::ChangeSettings) {
+        return (StatusCode::FORBIDDEN, "Not allowed to export this room").into_response();
+    }
+
+    let messages = match query.messages {
+        true => match state.db.get_messages(&room_id).await {
+            Ok(messages) => Some(messages),
+            Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load chat history").into_response(),
+        },
+        false => None,
+    };
+
+    Json(RoomExport {
+        version: EXPORT_VERSION,
+        exported_at: chrono::Utc::now().timestamp(),
+        room: ExportedRoom {
+            id: room.id,
+            name: room.name,
+            visibility: room.visibility,
+            description: room.description,
+            category: room.category,
+            tags: room.tags,
+            background: room.background,
+            max_users: room.max_users,
+            knock: room.knock,
+            objects: room.objects,
+        },
+        messages,
+    }).into_response()
+}
+
+/// Creates a room owned by the caller from an exported document. Objects
+/// and messages get new ids, so a document can be imported more than once,
+/// and authors new user ids, so it can't put words in local users' mouths.
+/// Nothing is stored unless all of it can be.
+pub async fn import_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Query(query): Query<ImportQuery>,
+    Json(document): Json<RoomExport>,
+) -> impl IntoResponse {
+    if document.version != EXPORT_VERSION {
+        return (StatusCode::BAD_REQUEST, "Unsupported export version").into_response();
+    }
+    if document.room.objects.len() > MAX_IMPORT_OBJECTS {
+        return (StatusCode::BAD_REQUEST, "Too many objects to import").into_response();
+    }
+    let messages = document.messages.unwrap_or_default();
+    if messages.len() > MAX_IMPORT_MESSAGES {
+        return (StatusCode::BAD_REQUEST, "Too many messages to import").into_response();
+    }
+    if document.room.max_users == Some(0) {
+        return (StatusCode::BAD_REQUEST, "maxUsers must be at least 1").into_response();
+    }
+    let mut ids: HashMap<String, String> = HashMap::new();
+    for message in &messages {
+        if ids.insert(message.id.clone(), uuid::Uuid::new_v4().to_string()).is_some() {
+            return (StatusCode::BAD_REQUEST, "Duplicate message id in chat history").into_response();
+        }
+    }
+    // Threads are one level deep: replies go under a message of the document that isn't a reply itself
+    let roots: HashMap<&str, bool> = messages.iter().map(|m| (m.id.as_str(), m.parent_id.is_none())).collect();
+    if messages.iter().any(|m| m.parent_id.as_deref().is_some_and(|p| roots.get(p) != Some(&true))) {
+        return (StatusCode::BAD_REQUEST, "Reply to a missing message in chat history").into_response();
+    }
+
+    let exported = document.room;
+    let payload = CreateRoom {
+        id: Some(query.id.unwrap_or(exported.id)),
+        name: query.name.unwrap_or(exported.name),
+        visibility: exported.visibility,
+        password: None,
+        description: exported.description,
+        category: exported.category,
+        tags: exported.tags,
+    };
+    let mut room = match api::new_room(&state, &user.account_id, payload).await {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    room.objects = templates::copy_objects(&exported.objects);
+    room.background = exported.background;
+    room.max_users = exported.max_users;
+    room.knock = exported.knock;
+
+    // The authors' accounts live in the other deployment, and so do
+    // whoever reacted
+    let mut authors: HashMap<String, String> = HashMap::new();
+    let messages: Vec<ChatMessage> = messages.into_iter()
+        .map(|message| ChatMessage {
+            id: ids[&message.id].clone(),
+            user_id: authors.entry(message.user_id.clone())
+                .or_insert_with(|| format!("imported-{}", uuid::Uuid::new_v4()))
+                .clone(),
+            account_id: None,
+            parent_id: message.parent_id.as_ref().map(|p| ids[p].clone()),
+            reactions: Vec::new(),
+            ..message
+        })
+        .collect();
+
+    match state.db.import_room(&room, &messages).await {
+        Ok(()) => api::announce_room(&state, &io, room).await,
+        Err(e) if db::is_unique_violation(&e) => (StatusCode::CONFLICT, "Room already exists").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to import room").into_response(),
+    }
+}
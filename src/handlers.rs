@@ -1,142 +1,534 @@
-use socketioxide::extract::{SocketRef, Data, State};
+use socketioxide::extract::{SocketRef, Data, State, TryData};
 use socketioxide::socket::DisconnectReason;
+use crate::auth;
+use crate::chat;
+use crate::dms;
+use crate::lobby;
+use crate::moderation;
+use crate::roles::{self, Permission, Role};
 use crate::state::AppState;
-use crate::types::{User, ChatMessage, DrawData, SignalPayload};
+use crate::types::{User, ChatMessage, DrawData, Identity, SignalPayload, ReturnSignalPayload, SocketSession, ParkedSession, Visibility, Knock};
+use serde::Deserialize;
 use serde_json::json;
+use tracing::{info, warn};
 
-pub async fn on_connect(socket: SocketRef, state: State<AppState>) {
-    println!("User connected: {}", socket.id);
-
-    // Helper to broadcast active rooms
-    let broadcast_active_rooms = |state: &AppState, socket: &SocketRef| {
-        let mut active_rooms = Vec::new();
-        for room in state.rooms.iter() {
-            if !room.users.is_empty() {
-                active_rooms.push(json!({
-                    "id": room.id,
-                    "name": room.name,
-                    "userCount": room.users.len(),
-                    "users": room.users.iter().map(|u| json!({ "name": u.name, "color": u.color })).collect::<Vec<_>>()
-                }));
+pub const HISTORY_PAGE_SIZE: usize = 50;
+
+#[derive(Deserialize)]
+pub struct HandshakeAuth {
+    token: Option<String>,
+    #[serde(rename = "guestToken")]
+    guest_token: Option<String>,
+    #[serde(rename = "recoveryId")]
+    recovery_id: Option<String>,
+}
+
+/// `join_room` arguments: room id, display name, and optionally
+/// `{ password, invite }` for rooms that need them.
+#[derive(Deserialize)]
+struct JoinRoom(String, String, #[serde(default)] Option<JoinOptions>);
+
+/// `send_chat` arguments: room id, text, and optionally the id of the
+/// message it replies to.
+#[derive(Deserialize)]
+struct SendChat(String, String, #[serde(default)] Option<String>);
+
+/// `load_thread` arguments: room id, the id of the message replied to, and
+/// optionally the timestamp (ms) and id of the oldest reply already loaded.
+#[derive(Deserialize)]
+struct LoadThread(String, String, #[serde(default)] Option<i64>, #[serde(default)] Option<String>);
+
+/// `load_more_messages` arguments: room id, and the timestamp (ms) and
+/// optionally the id of the oldest message already loaded.
+#[derive(Deserialize)]
+struct LoadMoreMessages(String, i64, #[serde(default)] Option<String>);
+
+#[derive(Deserialize, Default)]
+struct JoinOptions {
+    password: Option<String>,
+    invite: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct AuthError;
+
+impl std::fmt::Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid or expired token")
+    }
+}
+
+/// Connect middleware. A socket that presents a token (`auth.token` or `?token=`)
+/// must present a valid one, otherwise the connection is refused with a
+/// `connect_error`. Sockets without a token are let through as guests, keeping
+/// the guest id from `auth.guestToken` if they have one.
+pub async fn authenticate(socket: SocketRef, TryData(auth): TryData<HandshakeAuth>, state: State<AppState>) -> Result<(), AuthError> {
+    let mut auth = auth.ok();
+    let recovery_id = auth.as_mut().and_then(|a| a.recovery_id.take());
+    let token = auth.as_ref()
+        .and_then(|a| a.token.clone())
+        .or_else(|| {
+            socket.req_parts().uri.query().and_then(|q| {
+                q.split('&')
+                    .filter_map(|pair| pair.split_once('='))
+                    .find(|(k, _)| *k == "token")
+                    .map(|(_, v)| v.to_string())
+            })
+        })
+        .filter(|t| !t.is_empty());
+
+    let user_id = match token {
+        Some(token) => {
+            let claims = auth::verify_token(&state.keys, &token).map_err(|_| AuthError)?;
+            if !state.db.is_session_active(&claims.sid).await.unwrap_or(false) {
+                return Err(AuthError);
             }
+
+            state.identities.insert(socket.id.to_string(), Identity {
+                account_id: claims.sub.clone(),
+                username: claims.username,
+                session_id: claims.sid,
+            });
+            claims.sub
         }
-        let _ = socket.broadcast().emit("active_rooms", active_rooms.clone()); // Notify others
-        let _ = socket.emit("active_rooms", active_rooms); // Notify self
+        None => auth.and_then(|a| a.guest_token)
+            .and_then(|t| auth::verify_guest_token(&state.keys, &t))
+            .unwrap_or_else(|| format!("guest-{}", uuid::Uuid::new_v4())),
     };
 
+    // Keep the recovery id of a session this socket may resume (see on_connect)
+    let recovery_id = recovery_id
+        .filter(|id| state.parked.get(id).is_some_and(|p| p.user_id == user_id))
+        .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+    state.sockets.insert(socket.id.to_string(), SocketSession { user_id, room_id: None, recovery_id });
+    Ok(())
+}
+
+pub fn active_rooms(state: &AppState) -> Vec<serde_json::Value> {
+    let mut active_rooms = Vec::new();
+    for room in state.rooms.iter() {
+        if !room.users.is_empty() && room.visibility == Visibility::Public {
+            active_rooms.push(json!({
+                "id": room.id,
+                "name": room.name,
+                "userCount": room.users.len(),
+                "maxUsers": room.max_users,
+                "users": room.users.iter().map(|u| json!({ "name": u.name, "color": u.color })).collect::<Vec<_>>()
+            }));
+        }
+    }
+    active_rooms
+}
+
+/// Sends `event` to everyone else in the room, keeping a copy for sockets that
+/// are reconnecting into it.
+fn emit_to_room<T: serde::Serialize>(socket: &SocketRef, state: &AppState, room_id: &str, event: &'static str, data: T) {
+    state.buffer_event(room_id, event, &data);
+    let _ = socket.to(room_id.to_string()).emit(event, data);
+}
+
+/// A page of chat history: up to `limit` messages sent before `before` (or
+/// the latest ones), oldest first, and whether there are older ones.
+/// `before_id` breaks ties between messages sent in the same millisecond.
+pub async fn history_page(state: &AppState, room_id: &str, before: Option<i64>, before_id: Option<&str>, limit: usize) -> Result<serde_json::Value, sqlx::Error> {
+    let mut messages = state.db.get_messages_before(room_id, before, before_id, limit as i64 + 1).await?;
+    let has_more = messages.len() > limit;
+    if has_more {
+        messages.remove(0);
+    }
+    Ok(json!({ "roomId": room_id, "before": before, "beforeId": before_id, "messages": messages, "hasMore": has_more }))
+}
+
+/// Whether the socket is in `room_id` with a role that has `permission`. If
+/// not, it is told with a `permission_denied`.
+fn allowed(socket: &SocketRef, state: &AppState, room_id: &str, permission: Permission) -> bool {
+    if state.socket_role(&socket.id.to_string(), room_id).is_some_and(|role| role.can(permission)) {
+        return true;
+    }
+    let _ = socket.emit("permission_denied", json!({ "roomId": room_id, "permission": permission }));
+    false
+}
+
+fn chat_error(socket: &SocketRef, room_id: &str, message_id: &str, message: &str) {
+    let _ = socket.emit("chat_error", json!({ "roomId": room_id, "messageId": message_id, "message": message }));
+}
+
+fn dm_error(socket: &SocketRef, conversation_id: &str, message: &str) {
+    let _ = socket.emit("dm_error", json!({ "conversationId": conversation_id, "message": message }));
+}
+
+fn moderation_error(socket: &SocketRef, room_id: &str, user_id: &str, message: &str) {
+    let _ = socket.emit("moderation_error", json!({ "roomId": room_id, "userId": user_id, "message": message }));
+}
+
+/// Voice-muted users can't start or answer WebRTC connections.
+fn voice_muted(state: &AppState, session: &SocketSession) -> bool {
+    session.room_id.as_deref()
+        .and_then(|room_id| state.get_user(room_id, &session.user_id))
+        .is_some_and(|user| user.voice_muted)
+}
+
+/// The socket's session when it may tell `room_id` it is typing: it has to be
+/// in that room, and not muted in its chat.
+fn typing_session(socket: &SocketRef, state: &AppState, room_id: &str) -> Option<SocketSession> {
+    if room_id.contains(':') {
+        return None;
+    }
+    state.get_session(&socket.id.to_string())
+        .filter(|session| session.room_id.as_deref() == Some(room_id))
+        .filter(|session| !state.get_user(room_id, &session.user_id).is_some_and(|user| user.chat_muted))
+}
+
+/// The `socket:` rooms of `user_id`'s sockets in the same room as `session`;
+/// empty when the sender isn't in a room or the user isn't in theirs.
+fn peer_sockets(state: &AppState, session: &SocketSession, user_id: &str) -> Vec<String> {
+    let Some(room_id) = &session.room_id else {
+        return Vec::new();
+    };
+    state.sockets.iter()
+        .filter(|s| s.user_id == user_id && s.room_id.as_ref() == Some(room_id))
+        .map(|s| format!("socket:{}", s.key()))
+        .collect()
+}
+
+/// Takes the socket out of its current room.
+fn leave_current_room(socket: &SocketRef, state: &AppState) {
+    let socket_id = socket.id.to_string();
+    let Some(SocketSession { user_id, room_id: Some(room_id), .. }) = state.get_session(&socket_id) else {
+        return;
+    };
+
+    state.set_socket_room(&socket_id, None);
+    let _ = socket.leave(room_id.clone());
+    settle_presence(socket, state, &user_id, &room_id);
+}
+
+/// Once none of the user's sockets are left in the room, their presence goes
+/// (with a `user_left`), or waits as reconnecting while one of them is parked.
+fn settle_presence(socket: &SocketRef, state: &AppState, user_id: &str, room_id: &str) {
+    if state.user_present_elsewhere(user_id, room_id, &socket.id.to_string()) {
+        return;
+    }
+    if state.user_parked_in(user_id, room_id) {
+        if state.set_user_reconnecting(room_id, user_id, true) {
+            emit_to_room(socket, state, room_id, "user_reconnecting", user_id);
+        }
+    } else {
+        state.remove_user_from_room(room_id, user_id);
+        emit_to_room(socket, state, room_id, "user_left", user_id);
+    }
+}
+
+/// Puts a socket that resumed a parked session back in its room. The events
+/// it missed are left in the returned session for the caller to replay.
+fn resume_session(socket: &SocketRef, state: &AppState, session: &SocketSession) -> Option<ParkedSession> {
+    let parked = state.take_parked(&session.recovery_id, &session.user_id)?;
+    // Gone regardless (e.g. the room itself went away)
+    state.get_user(&parked.room_id, &parked.user_id)?;
+
+    socket.join(parked.room_id.clone());
+    state.set_socket_room(&socket.id.to_string(), Some(parked.room_id.clone()));
+    if state.set_user_reconnecting(&parked.room_id, &parked.user_id, false) {
+        emit_to_room(socket, state, &parked.room_id, "user_reconnected", &parked.user_id);
+    }
+    Some(parked)
+}
+
+pub async fn on_connect(socket: SocketRef, state: State<AppState>) {
+    let Some(session) = state.get_session(&socket.id.to_string()) else {
+        return;
+    };
+    let identity = state.get_identity(&socket.id.to_string());
+    match &identity {
+        Some(identity) => {
+            info!("User connected: {} (account {})", socket.id, identity.username);
+            // Lets logout / revocation find this socket
+            socket.join(vec![
+                format!("account:{}", identity.account_id),
+                format!("session:{}", identity.session_id),
+            ]);
+        }
+        None => info!("User connected: {} ({})", socket.id, session.user_id),
+    }
+    // Per-user messages are addressed to this room, so they reach every tab
+    // of the user; signals go to the one socket in the call
+    socket.join(vec![format!("user:{}", session.user_id), format!("socket:{}", socket.id)]);
+
+    let guest_token = match identity {
+        Some(_) => None,
+        None => auth::issue_guest_token(&state.keys, &session.user_id).ok(),
+    };
+    let resumed = resume_session(&socket, &state, &session);
+    let _ = socket.emit("session", json!({
+        "userId": session.user_id,
+        "accountId": identity.as_ref().map(|i| i.account_id.clone()),
+        "username": identity.as_ref().map(|i| i.username.clone()),
+        "guest": identity.is_none(),
+        "guestToken": guest_token,
+        "recoveryId": session.recovery_id,
+        "recovered": resumed.is_some(),
+        "roomId": resumed.as_ref().map(|p| p.room_id.clone()),
+    }));
+    // Replayed after the session event, so the client knows not to rejoin
+    for (event, data) in resumed.map(|p| p.events).unwrap_or_default() {
+        let _ = socket.emit(event, data);
+    }
+
     // Initial Active Rooms
-    broadcast_active_rooms(&state, &socket);
+    let rooms = active_rooms(&state);
+    let _ = socket.broadcast().emit("active_rooms", rooms.clone()); // Notify others
+    let _ = socket.emit("active_rooms", rooms); // Notify self
+
+    // So the client can show unread direct messages before opening any
+    if let Some(identity) = &identity {
+        if let Ok(total) = state.db.count_unread_direct_messages(&identity.account_id).await {
+            let _ = socket.emit("dm_unread", json!({ "total": total }));
+        }
+    }
+
+    socket.on("join_room", |socket: SocketRef, Data::<JoinRoom>(JoinRoom(room_id, name, options)), state: State<AppState>| async move {
+        let options = options.unwrap_or_default();
+        let socket_id = socket.id.to_string();
+
+        // ':' is reserved for the per-user/account/session rooms
+        if room_id.contains(':') {
+            let _ = socket.emit("join_error", json!({ "roomId": room_id, "reason": "invalid_room_id" }));
+            return;
+        }
+        let Some(session) = state.get_session(&socket_id) else {
+            return;
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        if let Ok(Some(ban)) = state.db.find_ban(&room_id, &session.user_id, now).await {
+            let _ = socket.emit("join_error", json!({ "roomId": room_id, "reason": "banned", "expiresAt": ban.expires_at }));
+            return;
+        }
+
+        // Users already in the room (from another tab) were let in before
+        if let Some(room) = state.get_room(&room_id) {
+            if !room.users.iter().any(|u| u.id == session.user_id) {
+                let account_id = state.get_identity(&socket_id).map(|i| i.account_id);
+                let mut by_password = false;
+                let access = match state.check_room_access(&room, account_id.as_deref(), options.invite.as_deref()) {
+                    Err("password_required") => match options.password.as_deref() {
+                        Some(password) => {
+                            by_password = true;
+                            state.check_room_password(&room, &session.user_id, password).await
+                        }
+                        None => Err("password_required"),
+                    },
+                    access => access,
+                };
+                if let Err(reason) = access {
+                    let _ = socket.emit("join_error", json!({ "roomId": room_id, "reason": reason }));
+                    return;
+                }
+                // An invite or the password only has to work once: accounts
+                // that use one become members, and can read the room's history
+                if let Some(account_id) = &account_id {
+                    let invited = options.invite.as_deref()
+                        .is_some_and(|token| auth::verify_invite_token(&state.keys, token, &room_id));
+                    if (invited || by_password) && room.owner_id.is_some() && room.owner_id.as_ref() != Some(account_id)
+                        && !room.roles.contains_key(account_id)
+                        && state.db.set_room_role(&room_id, account_id, Some(Role::Member)).await.is_ok()
+                    {
+                        state.set_room_role(&room_id, account_id, Some(Role::Member));
+                        let update = roles::role_update(&state, &room_id, account_id);
+                        state.buffer_event(&room_id, "role_updated", &update);
+                        let _ = socket.within(room_id.clone()).emit("role_updated", update);
+                    }
+                }
+
+                // Moderators skip the capacity limit and the lobby
+                let staff = room.allows(account_id.as_deref(), Permission::Moderate);
+                if !staff && room.max_users.is_some_and(|max| room.users.len() >= max) {
+                    let _ = socket.emit("join_error", json!({ "roomId": room_id, "reason": "room_full" }));
+                    return;
+                }
+                if !staff && room.knock && room.owner_id.is_some() && !state.take_admission(&room_id, &session.user_id) {
+                    let knock = Knock {
+                        user_id: session.user_id.clone(),
+                        name: state.get_identity(&socket_id).map(|i| i.username)
+                            .or(Some(name).filter(|n| !n.trim().is_empty()))
+                            .unwrap_or_else(|| "Guest".to_string()),
+                        socket_id: socket_id.clone(),
+                        since: now,
+                    };
+                    lobby::knock(&state, |room: String| socket.within(room), &room_id, knock);
+                    return;
+                }
+            }
+        }
+        lobby::withdraw(&state, |room: String| socket.within(room), &socket_id);
+        if session.room_id.as_deref() != Some(room_id.as_str()) {
+            leave_current_room(&socket, &state);
+        }
+        let user_id = session.user_id;
 
-    socket.on("join_room", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| {
-        let (room_id, name) = data;
-        
         socket.join(room_id.clone());
+        state.set_socket_room(&socket_id, Some(room_id.clone()));
 
-        let new_user = User {
-            id: socket.id.to_string(),
-            name,
-            color: format!("#{:06x}", rand::random::<u32>() & 0xFFFFFF),
-            x: rand::random::<f64>() * 800.0,
-            y: rand::random::<f64>() * 600.0,
-            room_id: room_id.clone(),
+        // Another tab (or the socket this one replaced) may still hold the
+        // user's presence here; otherwise pick up where they left off
+        let user = match state.get_user(&room_id, &user_id) {
+            Some(user) => user,
+            None => {
+                let identity = state.get_identity(&socket_id);
+                let saved = state.db.get_user(&user_id).await.unwrap_or(None);
+                let profile = match &identity {
+                    Some(identity) => state.db.get_profile(&identity.account_id).await.unwrap_or(None),
+                    None => None,
+                };
+
+                // Authenticated sockets join under their profile, not whatever the client sent
+                let name = match &identity {
+                    Some(identity) => profile.as_ref().and_then(|p| p.display_name.clone())
+                        .unwrap_or_else(|| identity.username.clone()),
+                    None => Some(name).filter(|n| !n.trim().is_empty())
+                        .or_else(|| saved.as_ref().map(|u| u.name.clone()))
+                        .unwrap_or_else(|| "Guest".to_string()),
+                };
+                let color = profile.and_then(|p| p.avatar_color)
+                    .or_else(|| saved.as_ref().map(|u| u.color.clone()))
+                    .unwrap_or_else(|| format!("#{:06x}", rand::random::<u32>() & 0xFFFFFF));
+                let (x, y) = match saved.filter(|u| u.room_id == room_id) {
+                    Some(u) => (u.x, u.y),
+                    None => (rand::random::<f64>() * 800.0, rand::random::<f64>() * 600.0),
+                };
+                let (chat_muted, voice_muted) = state.db.get_mutes(&room_id, &user_id).await.unwrap_or_default();
+
+                User {
+                    id: user_id.clone(),
+                    name,
+                    color,
+                    x,
+                    y,
+                    room_id: room_id.clone(),
+                    account_id: identity.map(|i| i.account_id),
+                    reconnecting: false,
+                    chat_muted,
+                    voice_muted,
+                }
+            }
         };
 
         // Add to state
-        state.add_user_to_room(room_id.clone(), new_user.clone());
+        let is_new = state.add_user_to_room(room_id.clone(), user.clone());
+        if !is_new && state.set_user_reconnecting(&room_id, &user_id, false) {
+            emit_to_room(&socket, &state, &room_id, "user_reconnected", &user_id);
+        }
         
         // Emit room state to user
         if let Some(room) = state.get_room(&room_id) {
+            let queue = state.lobby(&room_id);
+            let staff = room.allows(user.account_id.as_deref(), Permission::Moderate);
             let _ = socket.emit("room_state", room);
+            if staff && !queue.is_empty() {
+                let _ = socket.emit("lobby_updated", json!({ "roomId": room_id, "queue": queue }));
+            }
         }
 
         // Notify others
-        let _ = socket.to(room_id.clone()).emit("user_joined", new_user.clone());
+        if is_new {
+            emit_to_room(&socket, &state, &room_id, "user_joined", user.clone());
+        }
 
         // Broadcast active rooms update
-        let mut active_rooms = Vec::new();
-        for room in state.rooms.iter() {
-            if !room.users.is_empty() {
-                active_rooms.push(json!({
-                    "id": room.id,
-                    "name": room.name,
-                    "userCount": room.users.len(),
-                    "users": room.users.iter().map(|u| json!({ "name": u.name, "color": u.color })).collect::<Vec<_>>()
-                }));
-            }
-        }
-        let _ = socket.broadcast().emit("active_rooms", active_rooms);
+        let _ = socket.broadcast().emit("active_rooms", active_rooms(&state));
 
         // WebRTC: Send existing participants
         if let Some(room) = state.get_room(&room_id) { 
             let others: Vec<String> = room.users.iter()
-                .filter(|u| u.id != socket.id.to_string())
+                .filter(|u| u.id != user_id)
                 .map(|u| u.id.clone())
                 .collect();
             let _ = socket.emit("existing_participants", json!(vec![others]));
         }
 
-        // Send chat history
         let db = state.db.clone();
         let rid = room_id.clone();
-        let socket_clone = socket.clone();
         tokio::spawn(async move {
-            if let Ok(messages) = db.get_messages(&rid).await {
-                for msg in messages {
-                    let _ = socket_clone.emit("chat_message", msg);
-                }
+            if let Err(e) = db.save_user(&user, &rid).await {
+                warn!("Failed to save user: {}", e);
             }
+            // Lets the room directory sort by recent activity
+            let _ = db.touch_room(&rid, chrono::Utc::now().timestamp()).await;
         });
+
+        // Send the latest chat history; older pages come from load_more_messages
+        if let Ok(page) = history_page(&state, &room_id, None, None, HISTORY_PAGE_SIZE).await {
+            let _ = socket.emit("chat_history", page);
+        }
+    });
+
+    socket.on("load_more_messages", |socket: SocketRef, Data::<LoadMoreMessages>(LoadMoreMessages(room_id, before, before_id)), state: State<AppState>| async move {
+        match state.get_session(&socket.id.to_string()) {
+            Some(SocketSession { room_id: Some(current), .. }) if current == room_id => {}
+            _ => return,
+        }
+        if let Ok(page) = history_page(&state, &room_id, Some(before), before_id.as_deref(), HISTORY_PAGE_SIZE).await {
+            let _ = socket.emit("chat_history", page);
+        }
     });
 
-    socket.on("leave_room", |socket: SocketRef, Data::<String>(room_id), state: State<AppState>| {
-        state.remove_user(&socket.id.to_string());
-        let _ = socket.leave(room_id.clone());
-        let _ = socket.to(room_id).emit("user_left", socket.id.to_string());
+    socket.on("leave_room", |socket: SocketRef, Data::<String>(_room_id), state: State<AppState>| {
+        lobby::withdraw(&state, |room: String| socket.within(room), &socket.id.to_string());
+        leave_current_room(&socket, &state);
         
         // Broadcast active rooms update
-        let mut active_rooms = Vec::new();
-        for room in state.rooms.iter() {
-            if !room.users.is_empty() {
-                active_rooms.push(json!({
-                    "id": room.id,
-                    "name": room.name,
-                    "userCount": room.users.len(),
-                    "users": room.users.iter().map(|u| json!({ "name": u.name, "color": u.color })).collect::<Vec<_>>()
-                }));
-            }
-        }
-        let _ = socket.broadcast().emit("active_rooms", active_rooms);
+        let _ = socket.broadcast().emit("active_rooms", active_rooms(&state));
     });
 
     socket.on("move", |socket: SocketRef, Data::<(f64, f64)>(data), state: State<AppState>| async move {
         let (x, y) = data;
-        let user_id = socket.id.to_string();
-        if let Some(room_id) = state.get_user_room(&user_id) { 
-            state.update_user_position(&user_id, x, y); 
-            let _ = socket.to(room_id.clone()).emit("user_moved", (user_id.clone(), x, y));
+        let Some(SocketSession { user_id, room_id: Some(room_id), .. }) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        if let Some(user) = state.update_user_position(&room_id, &user_id, x, y) {
+            emit_to_room(&socket, &state, &room_id, "user_moved", (user_id.clone(), x, y));
             
-            if let Some(user) = state.get_user(&room_id, &user_id) { 
-                 let db = state.db.clone();
-                 let room_id_clone = room_id.clone();
-                 tokio::spawn(async move {
-                     if let Err(e) = db.save_user(&user, &room_id_clone).await {
-                         eprintln!("Failed to save user move: {}", e);
-                     }
-                 });
-            }
+            let db = state.db.clone();
+            tokio::spawn(async move {
+                if let Err(e) = db.save_user(&user, &room_id).await {
+                    warn!("Failed to save user move: {}", e);
+                }
+            });
         }
     });
 
-    socket.on("send_chat", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| {
-        let (room_id, text) = data;
-        let user_id = socket.id.to_string();
-        if let Some(user) = state.get_user(&room_id, &user_id) {
+    socket.on("send_chat", |socket: SocketRef, Data::<SendChat>(SendChat(room_id, text, parent_id)), state: State<AppState>| async move {
+        if !allowed(&socket, &state, &room_id, Permission::Chat) {
+            return;
+        }
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        if let Some(user) = state.get_user(&room_id, &session.user_id) {
+            if user.chat_muted {
+                let _ = socket.emit("permission_denied", json!({ "roomId": room_id, "permission": Permission::Chat, "reason": "muted" }));
+                return;
+            }
+            let parent_id = match parent_id {
+                Some(parent_id) => match chat::thread_root(&state, &room_id, &parent_id).await {
+                    Ok(root) => Some(root),
+                    Err(message) => return chat_error(&socket, &room_id, &parent_id, message),
+                },
+                None => None,
+            };
             let msg = ChatMessage {
                 id: uuid::Uuid::new_v4().to_string(),
                 user_id: user.id.clone(),
                 user_name: user.name.clone(),
                 text,
                 timestamp: chrono::Utc::now().timestamp_millis(),
+                account_id: user.account_id.clone(),
+                edited_at: None,
+                deleted_at: None,
+                parent_id,
+                reply_count: 0,
+                reactions: Vec::new(),
             };
             
             let db = state.db.clone();
@@ -147,112 +539,411 @@ pub async fn on_connect(socket: SocketRef, state: State<AppState>) {
             });
 
             // Emit to all in room including sender using within()
+            state.buffer_event(&room_id, "chat_message", &msg);
             let _ = socket.within(room_id).emit("chat_message", msg);
         }
     });
 
-    socket.on("draw_line", |socket: SocketRef, Data::<(String, DrawData)>(data)| {
+    socket.on("edit_chat", |socket: SocketRef, Data::<(String, String, String)>(data), state: State<AppState>| async move {
+        let (room_id, message_id, text) = data;
+        if !allowed(&socket, &state, &room_id, Permission::Chat) {
+            return;
+        }
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        // Editing would get around a chat mute
+        if state.get_user(&room_id, &session.user_id).is_some_and(|u| u.chat_muted) {
+            let _ = socket.emit("permission_denied", json!({ "roomId": room_id, "permission": Permission::Chat, "reason": "muted" }));
+            return;
+        }
+        match chat::edit(&state, &room_id, &message_id, &session.user_id, &text).await {
+            Ok(message) => {
+                let update = json!({ "roomId": room_id, "id": message.id, "text": message.text, "editedAt": message.edited_at });
+                state.buffer_event(&room_id, "chat_message_updated", &update);
+                let _ = socket.within(room_id).emit("chat_message_updated", update);
+            }
+            Err(message) => chat_error(&socket, &room_id, &message_id, message),
+        }
+    });
+
+    socket.on("delete_chat", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| async move {
+        let (room_id, message_id) = data;
+        let socket_id = socket.id.to_string();
+        if state.socket_role(&socket_id, &room_id).is_none() {
+            return;
+        }
+        let Some(session) = state.get_session(&socket_id) else {
+            return;
+        };
+        let account_id = state.get_identity(&socket_id).map(|i| i.account_id);
+        match chat::delete(&state, &room_id, &message_id, &session.user_id, account_id.as_deref()).await {
+            Ok(message) => {
+                let update = json!({ "roomId": room_id, "id": message.id, "deletedAt": message.deleted_at, "parentId": message.parent_id });
+                state.buffer_event(&room_id, "chat_message_deleted", &update);
+                let _ = socket.within(room_id).emit("chat_message_deleted", update);
+            }
+            Err(message) => chat_error(&socket, &room_id, &message_id, message),
+        }
+    });
+
+    socket.on("react_chat", |socket: SocketRef, Data::<(String, String, String)>(data), state: State<AppState>| async move {
+        let (room_id, message_id, emoji) = data;
+        if !allowed(&socket, &state, &room_id, Permission::React) {
+            return;
+        }
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        match chat::react(&state, &room_id, &message_id, &session.user_id, &emoji).await {
+            Ok((added, user_ids)) => {
+                let update = json!({
+                    "roomId": room_id,
+                    "messageId": message_id,
+                    "emoji": emoji.trim(),
+                    "userId": session.user_id,
+                    "added": added,
+                    "count": user_ids.len(),
+                    "userIds": user_ids,
+                });
+                state.buffer_event(&room_id, "chat_reaction_updated", &update);
+                let _ = socket.within(room_id).emit("chat_reaction_updated", update);
+            }
+            Err(message) => chat_error(&socket, &room_id, &message_id, message),
+        }
+    });
+
+    socket.on("load_thread", |socket: SocketRef, Data::<LoadThread>(LoadThread(room_id, message_id, before, before_id)), state: State<AppState>| async move {
+        match state.get_session(&socket.id.to_string()) {
+            Some(SocketSession { room_id: Some(current), .. }) if current == room_id => {}
+            _ => return,
+        }
+        match chat::thread_page(&state, &room_id, &message_id, before, before_id.as_deref(), HISTORY_PAGE_SIZE).await {
+            Ok(page) => {
+                let _ = socket.emit("chat_thread", page);
+            }
+            Err((_, message)) => chat_error(&socket, &room_id, &message_id, message),
+        }
+    });
+
+    socket.on("send_dm", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| async move {
+        let (conversation_id, text) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return dm_error(&socket, &conversation_id, "Sign in to send direct messages");
+        };
+        if let Err((_, message)) = dms::send(&state, |room: String| socket.within(room), &conversation_id, &identity.account_id, &text).await {
+            dm_error(&socket, &conversation_id, message);
+        }
+    });
+
+    socket.on("mark_dm_read", |socket: SocketRef, Data::<String>(conversation_id), state: State<AppState>| async move {
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return;
+        };
+        if let Err((_, message)) = dms::mark_read(&state, |room: String| socket.within(room), &conversation_id, &identity.account_id).await {
+            dm_error(&socket, &conversation_id, message);
+        }
+    });
+
+    socket.on("draw_line", |socket: SocketRef, Data::<(String, DrawData)>(data), state: State<AppState>| {
         let (room_id, draw_data) = data;
-        let _ = socket.to(room_id).emit("draw_line", draw_data);
+        if !allowed(&socket, &state, &room_id, Permission::Draw) {
+            return;
+        }
+        emit_to_room(&socket, &state, &room_id, "draw_line", draw_data);
     });
 
-    socket.on("share_embed", |socket: SocketRef, Data::<(String, Option<String>)>(data)| {
+    socket.on("share_embed", |socket: SocketRef, Data::<(String, Option<String>)>(data), state: State<AppState>| {
         let (room_id, url) = data;
-        let _ = socket.to(room_id).emit("update_embed", url);
+        if !allowed(&socket, &state, &room_id, Permission::ShareEmbed) {
+            return;
+        }
+        emit_to_room(&socket, &state, &room_id, "update_embed", url);
     });
 
     // Object Handlers
-    socket.on("add_object", |socket: SocketRef, Data::<(String, crate::types::RoomObject)>(data), state: State<AppState>| {
+    socket.on("add_object", |socket: SocketRef, Data::<(String, crate::types::RoomObject)>(data), state: State<AppState>| async move {
         let (room_id, object) = data;
-        state.add_object(room_id.clone(), object.clone());
-        let _ = socket.to(room_id).emit("object_added", object);
+        if !allowed(&socket, &state, &room_id, Permission::EditObjects) {
+            return;
+        }
+        if let Err(message) = state.add_object(&room_id, object.clone()).await {
+            let _ = socket.emit("object_error", json!({ "roomId": room_id, "objectId": object.id, "message": message }));
+            return;
+        }
+        emit_to_room(&socket, &state, &room_id, "object_added", object);
     });
 
     socket.on("update_object", |socket: SocketRef, Data::<(String, crate::types::RoomObject)>(data), state: State<AppState>| {
         let (room_id, object) = data;
+        if !allowed(&socket, &state, &room_id, Permission::EditObjects) {
+            return;
+        }
         state.update_object(room_id.clone(), object.clone());
-        let _ = socket.to(room_id).emit("object_updated", object);
+        emit_to_room(&socket, &state, &room_id, "object_updated", object);
     });
 
     socket.on("remove_object", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| {
         let (room_id, object_id) = data;
+        if !allowed(&socket, &state, &room_id, Permission::RemoveObjects) {
+            return;
+        }
         state.remove_object(room_id.clone(), object_id.clone());
-        let _ = socket.to(room_id).emit("object_removed", object_id);
+        emit_to_room(&socket, &state, &room_id, "object_removed", object_id);
     });
 
     socket.on("update_room_settings", |socket: SocketRef, Data::<(String, serde_json::Value)>(data), state: State<AppState>| {
         let (room_id, settings) = data;
+        if !allowed(&socket, &state, &room_id, Permission::ChangeSettings) {
+            return;
+        }
         if let Some(background) = settings.get("background").and_then(|v| v.as_str()) {
              state.update_room_background(room_id.clone(), Some(background.to_string()));
-             let _ = socket.to(room_id).emit("room_settings_updated", json!({ "background": background }));
+             emit_to_room(&socket, &state, &room_id, "room_settings_updated", json!({ "background": background }));
+        }
+        // null lifts the limit
+        match settings.get("maxUsers") {
+            Some(serde_json::Value::Null) => {
+                state.update_room_capacity(room_id.clone(), None);
+                emit_to_room(&socket, &state, &room_id, "room_settings_updated", json!({ "maxUsers": null }));
+            }
+            Some(value) => {
+                if let Some(max_users) = value.as_u64().filter(|m| *m >= 1).map(|m| m as usize) {
+                    state.update_room_capacity(room_id.clone(), Some(max_users));
+                    emit_to_room(&socket, &state, &room_id, "room_settings_updated", json!({ "maxUsers": max_users }));
+                }
+            }
+            None => {}
+        }
+        // Without an owner everyone is a moderator, so there'd be no one to knock
+        let owned = state.get_room(&room_id).is_some_and(|r| r.owner_id.is_some());
+        if let Some(knock) = settings.get("knock").and_then(|v| v.as_bool()).filter(|_| owned) {
+            state.update_room_knock(room_id.clone(), knock);
+            emit_to_room(&socket, &state, &room_id, "room_settings_updated", json!({ "knock": knock }));
+            if !knock {
+                lobby::admit_all(&state, |room: String| socket.within(room), &room_id);
+            }
+        }
+    });
+
+    socket.on("admit_knock", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| {
+        let (room_id, user_id) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return moderation_error(&socket, &room_id, &user_id, "Log in to moderate");
+        };
+        let within = |room: String| socket.within(room);
+        if let Err((_, message)) = lobby::answer(&state, within, &room_id, &identity.account_id, &user_id, true, None) {
+            moderation_error(&socket, &room_id, &user_id, message);
+        }
+    });
+
+    socket.on("deny_knock", |socket: SocketRef, Data::<(String, String, Option<String>)>(data), state: State<AppState>| {
+        let (room_id, user_id, reason) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return moderation_error(&socket, &room_id, &user_id, "Log in to moderate");
+        };
+        let within = |room: String| socket.within(room);
+        if let Err((_, message)) = lobby::answer(&state, within, &room_id, &identity.account_id, &user_id, false, reason) {
+            moderation_error(&socket, &room_id, &user_id, message);
         }
     });
 
-    socket.on("update_user", |socket: SocketRef, Data::<(String, String, String)>(data), state: State<AppState>| {
+    socket.on("set_role", |socket: SocketRef, Data::<(String, String, Option<Role>)>(data), state: State<AppState>| async move {
+        let (room_id, account_id, role) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            let _ = socket.emit("permission_denied", json!({ "roomId": room_id, "permission": Permission::ManageRoles }));
+            return;
+        };
+        match roles::assign(&state, &identity.account_id, &room_id, &account_id, role).await {
+            Ok(()) => {
+                let update = roles::role_update(&state, &room_id, &account_id);
+                state.buffer_event(&room_id, "role_updated", &update);
+                let _ = socket.within(room_id).emit("role_updated", update);
+            }
+            Err((_, message)) => {
+                let _ = socket.emit("role_error", json!({ "roomId": room_id, "accountId": account_id, "message": message }));
+            }
+        }
+    });
+
+    // Moderation
+    socket.on("kick_user", |socket: SocketRef, Data::<(String, String, Option<String>)>(data), state: State<AppState>| {
+        let (room_id, user_id, reason) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return moderation_error(&socket, &room_id, &user_id, "Log in to moderate");
+        };
+        let within = |room: String| socket.within(room);
+        if let Err((_, message)) = moderation::kick(&state, within, &room_id, &identity.account_id, &user_id, reason) {
+            moderation_error(&socket, &room_id, &user_id, message);
+        }
+    });
+
+    socket.on("ban_user", |socket: SocketRef, Data::<(String, String, Option<moderation::BanOptions>)>(data), state: State<AppState>| async move {
+        let (room_id, user_id, options) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return moderation_error(&socket, &room_id, &user_id, "Log in to moderate");
+        };
+        let within = |room: String| socket.within(room);
+        let result = moderation::ban(&state, within, &room_id, &identity.account_id, &user_id, options.unwrap_or_default()).await;
+        if let Err((_, message)) = result {
+            moderation_error(&socket, &room_id, &user_id, message);
+        }
+    });
+
+    socket.on("mute_user", |socket: SocketRef, Data::<(String, String, moderation::MuteOptions)>(data), state: State<AppState>| async move {
+        let (room_id, user_id, options) = data;
+        let Some(identity) = state.get_identity(&socket.id.to_string()) else {
+            return moderation_error(&socket, &room_id, &user_id, "Log in to moderate");
+        };
+        let within = |room: String| socket.within(room);
+        if let Err((_, message)) = moderation::mute(&state, within, &room_id, &identity.account_id, &user_id, options).await {
+            moderation_error(&socket, &room_id, &user_id, message);
+        }
+    });
+
+    socket.on("report_user", |socket: SocketRef, Data::<(String, String, String)>(data), state: State<AppState>| async move {
+        let (room_id, user_id, reason) = data;
+        if !allowed(&socket, &state, &room_id, Permission::Report) {
+            return;
+        }
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        match moderation::report(&state, &room_id, &session.user_id, &user_id, &reason).await {
+            Ok(report) => {
+                let _ = socket.emit("report_filed", json!({ "id": report.id, "roomId": room_id }));
+            }
+            Err((_, message)) => moderation_error(&socket, &room_id, &user_id, message),
+        }
+    });
+
+    socket.on("update_user", |socket: SocketRef, Data::<(String, String, String)>(data), state: State<AppState>| async move {
         let (_room_id, name, color) = data;
-        let user_id = socket.id.to_string();
-        if let Some((room_id, updated_user)) = state.update_user_details(&user_id, Some(name), Some(color)) {
-            let _ = socket.to(room_id.clone()).emit("user_updated", updated_user.clone());
-            let _ = socket.emit("user_updated", updated_user);
+        let socket_id = socket.id.to_string();
+        let Some(SocketSession { user_id, room_id: Some(room_id), .. }) = state.get_session(&socket_id) else {
+            return;
+        };
+        // As on join, authenticated sockets go by their profile name
+        let name = match state.get_identity(&socket_id) {
+            Some(identity) => match state.db.get_profile(&identity.account_id).await {
+                Ok(Some(profile)) => profile.display_name.unwrap_or(identity.username),
+                _ => return,
+            },
+            None => name,
+        };
+        if let Some(updated_user) = state.update_user_details(&room_id, &user_id, Some(name), Some(color)) {
+            emit_to_room(&socket, &state, &room_id, "user_updated", updated_user.clone());
+            let _ = socket.emit("user_updated", updated_user.clone());
+
+            let db = state.db.clone();
+            tokio::spawn(async move {
+                let _ = db.save_user(&updated_user, &room_id).await;
+            });
         }
     });
 
-    socket.on("send_emoji", |socket: SocketRef, Data::<(String, String)>(data)| {
+    socket.on("send_emoji", |socket: SocketRef, Data::<(String, String)>(data), state: State<AppState>| {
         let (room_id, emoji) = data;
-        let _ = socket.to(room_id).emit("emoji_reaction", json!({
+        if !allowed(&socket, &state, &room_id, Permission::React) {
+            return;
+        }
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        emit_to_room(&socket, &state, &room_id, "emoji_reaction", json!({
             "emoji": emoji,
-            "userId": socket.id.to_string()
+            "userId": session.user_id
         }));
     });
 
-    socket.on("typing", |socket: SocketRef, Data::<String>(room_id)| {
-        let _ = socket.to(room_id).emit("user_typing", socket.id.to_string());
+    socket.on("typing", |socket: SocketRef, Data::<String>(room_id), state: State<AppState>| {
+        if let Some(session) = typing_session(&socket, &state, &room_id) {
+            let _ = socket.to(room_id).emit("user_typing", session.user_id);
+        }
     });
 
-    socket.on("stop_typing", |socket: SocketRef, Data::<String>(room_id)| {
-        let _ = socket.to(room_id).emit("user_stop_typing", socket.id.to_string());
+    socket.on("stop_typing", |socket: SocketRef, Data::<String>(room_id), state: State<AppState>| {
+        if let Some(session) = typing_session(&socket, &state, &room_id) {
+            let _ = socket.to(room_id).emit("user_stop_typing", session.user_id);
+        }
     });
 
     // WebRTC Signaling
-    socket.on("sending_signal", |socket: SocketRef, Data::<SignalPayload>(payload)| {
+    // Peers are addressed by user id, and only reach the peer's sockets in the
+    // sender's room; the caller is always the sender's own id
+    socket.on("sending_signal", |socket: SocketRef, Data::<SignalPayload>(payload), state: State<AppState>| {
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        if voice_muted(&state, &session) {
+            return;
+        }
         if let Some(user_to_signal) = payload.user_to_signal {
-             let _ = socket.to(user_to_signal.clone()).emit("user_connected", payload.caller_id.clone());
-             let _ = socket.to(user_to_signal).emit("signal_received", json!({
+             let target = peer_sockets(&state, &session, &user_to_signal);
+             if target.is_empty() {
+                 return;
+             }
+             let _ = socket.to(target.clone()).emit("user_connected", session.user_id.clone());
+             let _ = socket.to(target).emit("signal_received", json!({
                  "signal": payload.signal,
-                 "callerID": payload.caller_id
+                 "callerID": session.user_id
              }));
         }
     });
 
-    socket.on("returning_signal", |socket: SocketRef, Data::<SignalPayload>(payload)| {
-        let _ = socket.to(payload.caller_id).emit("return_signal", json!({
-            "signal": payload.signal,
-            "id": socket.id.to_string()
-        }));
+    socket.on("returning_signal", |socket: SocketRef, Data::<SignalPayload>(payload), state: State<AppState>| {
+        let Some(session) = state.get_session(&socket.id.to_string()) else {
+            return;
+        };
+        if voice_muted(&state, &session) {
+            return;
+        }
+        let target = peer_sockets(&state, &session, &payload.caller_id);
+        if target.is_empty() {
+            return;
+        }
+        let _ = socket.to(target).emit("return_signal", ReturnSignalPayload {
+            signal: payload.signal,
+            id: session.user_id,
+        });
     });
 
     socket.on_disconnect(|socket: SocketRef, reason: DisconnectReason, state: State<AppState>| {
-        println!("User disconnected: {} ({:?})", socket.id, reason);
-        state.remove_user(&socket.id.to_string());
-        
-        // Notify all rooms
-        for room in state.rooms.iter() {
-             println!("Notifying room {} about user left: {}", room.key(), socket.id);
-             let _ = socket.to(room.key().clone()).emit("user_left", socket.id.to_string());
-        }
+        info!("User disconnected: {} ({:?})", socket.id, reason);
+        // Unless the client or the server ended the session on purpose, hold
+        // the user's place for a while in case the connection comes back
+        let recoverable = !matches!(reason,
+            DisconnectReason::ClientNSDisconnect | DisconnectReason::ServerNSDisconnect | DisconnectReason::ClosingServer);
+        let parked = match state.get_session(&socket.id.to_string()) {
+            Some(SocketSession { user_id, room_id: Some(room_id), recovery_id }) if recoverable => {
+                state.park_socket(&recovery_id, &user_id, &room_id);
+                Some(recovery_id)
+            }
+            _ => None,
+        };
+        lobby::withdraw(&state, |room: String| socket.within(room), &socket.id.to_string());
+        leave_current_room(&socket, &state);
+        state.sockets.remove(&socket.id.to_string());
+        state.identities.remove(&socket.id.to_string());
 
-        // Broadcast active rooms update
-        let mut active_rooms = Vec::new();
-        for room in state.rooms.iter() {
-            if !room.users.is_empty() {
-                active_rooms.push(json!({
-                    "id": room.id,
-                    "name": room.name,
-                    "userCount": room.users.len(),
-                    "users": room.users.iter().map(|u| json!({ "name": u.name, "color": u.color })).collect::<Vec<_>>()
-                }));
+        if let Some(recovery_id) = parked {
+            // Its own user_reconnecting needn't be replayed to it
+            if let Some(mut parked) = state.parked.get_mut(&recovery_id) {
+                parked.events.clear();
             }
+
+            let socket = socket.clone();
+            let state = AppState::clone(&state);
+            tokio::spawn(async move {
+                tokio::time::sleep(state.reconnect_grace).await;
+                if let Some(expired) = state.expire_parked(&recovery_id) {
+                    settle_presence(&socket, &state, &expired.user_id, &expired.room_id);
+                    let _ = socket.broadcast().emit("active_rooms", active_rooms(&state));
+                }
+            });
         }
-        let _ = socket.broadcast().emit("active_rooms", active_rooms);
+
+        // Broadcast active rooms update
+        let _ = socket.broadcast().emit("active_rooms", active_rooms(&state));
     });
 }
@@ -0,0 +1,256 @@
+use jsonwebtoken::{
+    decode, decode_header, encode, errors::ErrorKind, Algorithm, DecodingKey, EncodingKey, Header, Validation,
+};
+use serde::{de::DeserializeOwned, Serialize};
+use std::error::Error;
+use std::str::FromStr;
+use tracing::{info, warn};
+
+// Signing configuration, all read from the environment:
+//
+//   JWT_ALGORITHM             HS256 (default), HS384, HS512, RS256, PS256, ES256, EdDSA, ...
+//   JWT_KEY_ID                `kid` put in the header of every token we sign (default "default")
+//   JWT_SECRET                HMAC secret, for the HS* algorithms
+//   JWT_PRIVATE_KEY           PEM private key, for everything else
+//   JWT_PUBLIC_KEY            PEM public key matching JWT_PRIVATE_KEY
+//
+// A key that was rotated out can still verify tokens for a while:
+//
+//   JWT_PREVIOUS_KEY_ID       `kid` of the old key
+//   JWT_PREVIOUS_ALGORITHM    defaults to JWT_ALGORITHM
+//   JWT_PREVIOUS_SECRET       or JWT_PREVIOUS_PUBLIC_KEY
+//   JWT_PREVIOUS_KEY_UNTIL    RFC 3339 timestamp after which the old key is refused
+//
+// Every variable holding key material can instead be given as `<NAME>_FILE`
+// pointing at a file to read it from.
+
+struct VerifyingKey {
+    kid: String,
+    algorithm: Algorithm,
+    key: DecodingKey,
+    // Only set for rotated-out keys
+    valid_until: Option<i64>,
+}
+
+pub struct KeyRing {
+    kid: String,
+    algorithm: Algorithm,
+    signing_key: EncodingKey,
+    verifying_keys: Vec<VerifyingKey>,
+}
+
+impl KeyRing {
+    pub fn from_env() -> Result<Self, Box<dyn Error>> {
+        let algorithm = algorithm_var("JWT_ALGORITHM")?.unwrap_or(Algorithm::HS256);
+        let kid = std::env::var("JWT_KEY_ID").unwrap_or_else(|_| "default".to_string());
+
+        let (signing_key, verifying_key) = if is_hmac(algorithm) {
+            let secret = match config_var("JWT_SECRET")? {
+                Some(secret) => secret,
+                None => {
+                    warn!("JWT_SECRET is not set, using a random secret; tokens will not survive a restart");
+                    uuid::Uuid::new_v4().to_string()
+                }
+            };
+            (EncodingKey::from_secret(secret.as_bytes()), DecodingKey::from_secret(secret.as_bytes()))
+        } else {
+            let private_pem = config_var("JWT_PRIVATE_KEY")?
+                .ok_or("JWT_PRIVATE_KEY is required for asymmetric algorithms")?;
+            let public_pem = config_var("JWT_PUBLIC_KEY")?
+                .ok_or("JWT_PUBLIC_KEY is required for asymmetric algorithms")?;
+            (encoding_key_from_pem(algorithm, &private_pem)?, decoding_key_from_pem(algorithm, &public_pem)?)
+        };
+
+        let mut verifying_keys = vec![VerifyingKey {
+            kid: kid.clone(),
+            algorithm,
+            key: verifying_key,
+            valid_until: None,
+        }];
+
+        if let Ok(previous_kid) = std::env::var("JWT_PREVIOUS_KEY_ID") {
+            if previous_kid == kid {
+                return Err("JWT_PREVIOUS_KEY_ID must differ from JWT_KEY_ID".into());
+            }
+            let previous_algorithm = algorithm_var("JWT_PREVIOUS_ALGORITHM")?.unwrap_or(algorithm);
+            let key = if is_hmac(previous_algorithm) {
+                let secret = config_var("JWT_PREVIOUS_SECRET")?
+                    .ok_or("JWT_PREVIOUS_SECRET is required when JWT_PREVIOUS_KEY_ID is set")?;
+                DecodingKey::from_secret(secret.as_bytes())
+            } else {
+                let public_pem = config_var("JWT_PREVIOUS_PUBLIC_KEY")?
+                    .ok_or("JWT_PREVIOUS_PUBLIC_KEY is required when JWT_PREVIOUS_KEY_ID is set")?;
+                decoding_key_from_pem(previous_algorithm, &public_pem)?
+            };
+            let valid_until = match std::env::var("JWT_PREVIOUS_KEY_UNTIL") {
+                Ok(until) => Some(chrono::DateTime::parse_from_rfc3339(&until)
+                    .map_err(|e| format!("JWT_PREVIOUS_KEY_UNTIL: {}", e))?
+                    .timestamp()),
+                Err(_) => None,
+            };
+
+            info!("Accepting tokens signed with previous key '{}'", previous_kid);
+            verifying_keys.push(VerifyingKey {
+                kid: previous_kid,
+                algorithm: previous_algorithm,
+                key,
+                valid_until,
+            });
+        }
+
+        info!("Signing tokens with {:?} key '{}'", algorithm, kid);
+        Ok(Self {
+            kid,
+            algorithm,
+            signing_key,
+            verifying_keys,
+        })
+    }
+
+    pub fn sign<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, &self.signing_key)
+    }
+
+    /// Verifies a token against the key named by its `kid` header. Tokens
+    /// without a `kid` are checked against the current key only.
+    pub fn verify<T: DeserializeOwned>(&self, token: &str) -> Result<T, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        let kid = header.kid.as_deref().unwrap_or(&self.kid);
+
+        let key = self.verifying_keys.iter()
+            .find(|k| k.kid == kid)
+            .ok_or(ErrorKind::InvalidSignature)?;
+
+        if let Some(until) = key.valid_until {
+            if chrono::Utc::now().timestamp() > until {
+                return Err(ErrorKind::InvalidSignature.into());
+            }
+        }
+
+        decode::<T>(token, &key.key, &Validation::new(key.algorithm)).map(|data| data.claims)
+    }
+}
+
+fn is_hmac(algorithm: Algorithm) -> bool {
+    matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512)
+}
+
+fn algorithm_var(name: &str) -> Result<Option<Algorithm>, Box<dyn Error>> {
+    match std::env::var(name) {
+        Ok(value) => Algorithm::from_str(&value)
+            .map(Some)
+            .map_err(|_| format!("{}: unsupported algorithm '{}'", name, value).into()),
+        Err(_) => Ok(None),
+    }
+}
+
+/// Reads `name` from the environment, or the file named by `<name>_FILE`.
+fn config_var(name: &str) -> Result<Option<String>, Box<dyn Error>> {
+    if let Ok(value) = std::env::var(name) {
+        return Ok(Some(value));
+    }
+    match std::env::var(format!("{}_FILE", name)) {
+        Ok(path) => std::fs::read_to_string(&path)
+            .map(Some)
+            .map_err(|e| format!("{}_FILE ({}): {}", name, path, e).into()),
+        Err(_) => Ok(None),
+    }
+}
+
+fn encoding_key_from_pem(algorithm: Algorithm, pem: &str) -> Result<EncodingKey, Box<dyn Error>> {
+    let key = match algorithm {
+        Algorithm::EdDSA => EncodingKey::from_ed_pem(pem.as_bytes()),
+        Algorithm::ES256 | Algorithm::ES384 => EncodingKey::from_ec_pem(pem.as_bytes()),
+        _ => EncodingKey::from_rsa_pem(pem.as_bytes()),
+    };
+    key.map_err(|e| format!("invalid private key: {}", e).into())
+}
+
+fn decoding_key_from_pem(algorithm: Algorithm, pem: &str) -> Result<DecodingKey, Box<dyn Error>> {
+    let key = match algorithm {
+        Algorithm::EdDSA => DecodingKey::from_ed_pem(pem.as_bytes()),
+        Algorithm::ES256 | Algorithm::ES384 => DecodingKey::from_ec_pem(pem.as_bytes()),
+        _ => DecodingKey::from_rsa_pem(pem.as_bytes()),
+    };
+    key.map_err(|e| format!("invalid public key: {}", e).into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct TestClaims {
+        sub: String,
+        exp: usize,
+    }
+
+    fn claims() -> TestClaims {
+        TestClaims { sub: "acc-1".into(), exp: (chrono::Utc::now().timestamp() + 60) as usize }
+    }
+
+    fn hmac_key(kid: &str, secret: &str, valid_until: Option<i64>) -> VerifyingKey {
+        VerifyingKey {
+            kid: kid.into(),
+            algorithm: Algorithm::HS256,
+            key: DecodingKey::from_secret(secret.as_bytes()),
+            valid_until,
+        }
+    }
+
+    fn ring(kid: &str, secret: &str, previous: Option<VerifyingKey>) -> KeyRing {
+        KeyRing {
+            kid: kid.into(),
+            algorithm: Algorithm::HS256,
+            signing_key: EncodingKey::from_secret(secret.as_bytes()),
+            verifying_keys: std::iter::once(hmac_key(kid, secret, None)).chain(previous).collect(),
+        }
+    }
+
+    #[test]
+    fn tokens_are_signed_with_the_current_kid() {
+        let keys = ring("new", "new-secret", None);
+        let token = keys.sign(&claims()).unwrap();
+        assert_eq!(decode_header(&token).unwrap().kid.as_deref(), Some("new"));
+        assert_eq!(keys.verify::<TestClaims>(&token).unwrap(), claims());
+    }
+
+    #[test]
+    fn rotated_out_keys_verify_until_their_deadline() {
+        let old = ring("old", "old-secret", None);
+        let token = old.sign(&claims()).unwrap();
+
+        let in_time = chrono::Utc::now().timestamp() + 60;
+        let keys = ring("new", "new-secret", Some(hmac_key("old", "old-secret", Some(in_time))));
+        assert!(keys.verify::<TestClaims>(&token).is_ok());
+
+        let too_late = chrono::Utc::now().timestamp() - 60;
+        let keys = ring("new", "new-secret", Some(hmac_key("old", "old-secret", Some(too_late))));
+        assert!(keys.verify::<TestClaims>(&token).is_err());
+
+        // Once the old key is dropped from the ring entirely
+        assert!(ring("new", "new-secret", None).verify::<TestClaims>(&token).is_err());
+    }
+
+    #[test]
+    fn the_kid_picks_the_key() {
+        let keys = ring("new", "new-secret", Some(hmac_key("old", "old-secret", None)));
+
+        // Signed with the old secret but claiming to be the new key
+        let forged = ring("new", "old-secret", None).sign(&claims()).unwrap();
+        assert!(keys.verify::<TestClaims>(&forged).is_err());
+
+        // No kid at all: checked against the current key only
+        let header = Header::new(Algorithm::HS256);
+        let current = encode(&header, &claims(), &EncodingKey::from_secret(b"new-secret")).unwrap();
+        let previous = encode(&header, &claims(), &EncodingKey::from_secret(b"old-secret")).unwrap();
+        assert!(keys.verify::<TestClaims>(&current).is_ok());
+        assert!(keys.verify::<TestClaims>(&previous).is_err());
+
+        let unknown = ring("other", "new-secret", None).sign(&claims()).unwrap();
+        assert!(keys.verify::<TestClaims>(&unknown).is_err());
+    }
+}
@@ -0,0 +1,91 @@
+use std::time::Duration;
+use axum::http::StatusCode;
+use serde_json::json;
+use socketioxide::operators::BroadcastOperators;
+use crate::roles::Permission;
+use crate::state::AppState;
+use crate::types::Knock;
+
+// How long an admitted user has to actually join before knocking again
+const ADMISSION_TTL: Duration = Duration::from_secs(5 * 60);
+
+/// Puts the socket's user in the room's lobby. Knocking somewhere else gives
+/// up any place the socket held in another queue.
+pub fn knock(state: &AppState, within: impl Fn(String) -> BroadcastOperators, room_id: &str, knock: Knock) {
+    let socket_id = knock.socket_id.clone();
+    let left: Vec<String> = state.withdraw_knocks(&socket_id).into_iter().filter(|r| r != room_id).collect();
+    state.knock(room_id, knock);
+    for room in left {
+        notify(state, &within, &room);
+    }
+    notify(state, &within, room_id);
+}
+
+/// Tells everyone waiting for the room where they are in the queue, and the
+/// moderators in the room who is waiting.
+pub fn notify(state: &AppState, within: &impl Fn(String) -> BroadcastOperators, room_id: &str) {
+    let queue = state.lobby(room_id);
+    for (index, knock) in queue.iter().enumerate() {
+        let _ = within(format!("user:{}", knock.user_id))
+            .emit("knock_pending", json!({ "roomId": room_id, "position": index + 1 }));
+    }
+
+    let Some(room) = state.get_room(room_id) else {
+        return;
+    };
+    for user in room.users.iter().filter(|u| room.allows(u.account_id.as_deref(), Permission::Moderate)) {
+        let _ = within(format!("user:{}", user.id)).emit("lobby_updated", json!({ "roomId": room_id, "queue": queue }));
+    }
+}
+
+/// Lets a waiting user in, or turns them away. Admitted users get a
+/// `knock_admitted` and can then join the room as usual.
+pub fn answer(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    room_id: &str,
+    actor: &str,
+    user_id: &str,
+    admit: bool,
+    reason: Option<String>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if !room.allows(Some(actor), Permission::Moderate) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to moderate this room"));
+    }
+    if state.take_knock(room_id, user_id).is_none() {
+        return Err((StatusCode::NOT_FOUND, "User is not waiting to join"));
+    }
+
+    let user_room = format!("user:{}", user_id);
+    if admit {
+        state.admit(room_id, user_id, ADMISSION_TTL);
+        let _ = within(user_room).emit("knock_admitted", json!({ "roomId": room_id }));
+    } else {
+        let _ = within(user_room).emit("knock_denied", json!({ "roomId": room_id, "reason": reason }));
+    }
+    notify(state, &within, room_id);
+    Ok(())
+}
+
+/// Lets in everyone waiting, for when knocking is turned off.
+pub fn admit_all(state: &AppState, within: impl Fn(String) -> BroadcastOperators, room_id: &str) {
+    let queue = state.take_lobby(room_id);
+    if queue.is_empty() {
+        return;
+    }
+    for knock in queue {
+        state.admit(room_id, &knock.user_id, ADMISSION_TTL);
+        let _ = within(format!("user:{}", knock.user_id)).emit("knock_admitted", json!({ "roomId": room_id }));
+    }
+    notify(state, &within, room_id);
+}
+
+/// Takes the socket out of any lobby it is waiting in.
+pub fn withdraw(state: &AppState, within: impl Fn(String) -> BroadcastOperators, socket_id: &str) {
+    for room_id in state.withdraw_knocks(socket_id) {
+        notify(state, &within, &room_id);
+    }
+}
@@ -1,5 +1,5 @@
-use axum::Router;
-use socketioxide::SocketIo;
+use axum::{extract::DefaultBodyLimit, middleware, Extension, Router};
+use socketioxide::{handler::ConnectHandler, SocketIo};
 use tower::ServiceBuilder;
 use tower_http::cors::CorsLayer;
 use tracing::info;
@@ -12,6 +12,20 @@ mod types;
 mod db;
 mod api;
 mod auth;
+mod account;
+mod two_factor;
+mod oidc;
+mod keys;
+mod throttle;
+mod roles;
+mod moderation;
+mod lobby;
+mod templates;
+mod snapshots;
+mod export;
+mod chat;
+mod dms;
+mod search;
 
 use state::AppState;
 
@@ -36,24 +50,102 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
         }
     });
     info!("Using database: {}", database_url);
-    let state = AppState::new(&database_url).await?;
+    let keys = keys::KeyRing::from_env()?;
+    let state = AppState::new(&database_url, keys, oidc::OidcClient::from_env()).await?;
+    // Hash it now so the first login for an unknown user isn't the slow one
+    tokio::task::spawn_blocking(auth::dummy_hash);
+    if let Some(interval) = state.snapshot_interval {
+        tokio::spawn(snapshots::run_periodic(state.clone(), interval));
+    }
 
     // Setup Socket.IO
     let (layer, io) = SocketIo::builder()
         .with_state(state.clone())
         .build_layer();
 
-    io.ns("/", handlers::on_connect);
+    io.ns("/", handlers::on_connect.with(handlers::authenticate));
 
     // Setup Axum Router
-    let app = Router::new()
+    let public = Router::new()
         .route("/api/register", axum::routing::post(auth::register))
         .route("/api/login", axum::routing::post(auth::login))
-        .route("/api/rooms", axum::routing::get(api::list_rooms))
+        .route("/api/login/2fa", axum::routing::post(auth::login_2fa))
+        .route("/api/refresh", axum::routing::post(auth::refresh))
+        .route("/api/oidc/login", axum::routing::get(oidc::login))
+        .route("/api/oidc/callback", axum::routing::get(oidc::callback))
+        .route("/api/oidc/exchange", axum::routing::post(oidc::exchange));
+
+    // Everything in here requires `Authorization: Bearer <token>`
+    let protected = Router::new()
+        .route("/api/me", axum::routing::get(auth::me))
+        .route("/api/logout", axum::routing::post(auth::logout))
+        .route(
+            "/api/account",
+            axum::routing::get(account::get_profile)
+                .patch(account::update_profile)
+                .delete(account::delete_account),
+        )
+        .route("/api/account/password", axum::routing::post(account::change_password))
+        .route("/api/account/2fa/setup", axum::routing::post(two_factor::setup))
+        .route("/api/account/2fa/confirm", axum::routing::post(two_factor::confirm))
+        .route("/api/account/2fa/disable", axum::routing::post(two_factor::disable))
+        .route("/api/account/oidc/link", axum::routing::post(oidc::link))
+        .route("/api/rooms", axum::routing::get(api::list_rooms).post(api::create_room))
+        .route(
+            "/api/rooms/import",
+            axum::routing::post(export::import_room).layer(DefaultBodyLimit::max(export::MAX_IMPORT_BYTES)),
+        )
+        .route(
+            "/api/rooms/:id",
+            axum::routing::get(api::get_room)
+                .patch(api::update_room)
+                .delete(api::delete_room),
+        )
+        .route("/api/rooms/:id/invites", axum::routing::post(api::create_invite))
+        .route("/api/rooms/:id/messages", axum::routing::get(api::get_messages))
+        .route("/api/rooms/:id/messages/:message_id/thread", axum::routing::get(api::get_thread))
+        .route("/api/messages/search", axum::routing::get(search::search_messages))
+        .route("/api/rooms/:id/bans", axum::routing::get(moderation::list_bans))
+        .route("/api/rooms/:id/bans/:user_id", axum::routing::delete(moderation::unban))
+        .route("/api/admin/reports", axum::routing::get(moderation::list_reports))
+        .route("/api/admin/reports/:id/resolve", axum::routing::post(moderation::resolve_report))
+        .route("/api/rooms/:id/roles", axum::routing::get(roles::list))
+        .route(
+            "/api/rooms/:id/roles/:account_id",
+            axum::routing::put(roles::grant).delete(roles::revoke),
+        )
+        .route("/api/rooms/:id/clone", axum::routing::post(templates::clone_room))
+        .route("/api/rooms/:id/export", axum::routing::get(export::export_room))
+        .route(
+            "/api/rooms/:id/snapshots",
+            axum::routing::get(snapshots::list).post(snapshots::create),
+        )
+        .route("/api/rooms/:id/snapshots/:snapshot_id", axum::routing::get(snapshots::get))
+        .route("/api/rooms/:id/snapshots/:snapshot_id/diff", axum::routing::get(snapshots::diff))
+        .route("/api/rooms/:id/snapshots/:snapshot_id/restore", axum::routing::post(snapshots::restore))
+        .route("/api/templates", axum::routing::get(templates::list).post(templates::save))
+        .route(
+            "/api/templates/:id",
+            axum::routing::get(templates::get).delete(templates::delete),
+        )
+        .route("/api/templates/:id/rooms", axum::routing::post(templates::create_room))
+        .route("/api/dms", axum::routing::get(dms::list).post(dms::create))
+        .route(
+            "/api/dms/:id/messages",
+            axum::routing::get(dms::messages).post(dms::send_message),
+        )
+        .route("/api/dms/:id/read", axum::routing::post(dms::read))
+        .route_layer(middleware::from_fn_with_state(state.clone(), auth::require_auth));
+
+    let app = Router::new()
+        .merge(public)
+        .merge(protected)
         .with_state(state)
+        .layer(Extension(io))
         .layer(
             ServiceBuilder::new()
-                .layer(CorsLayer::permissive()) // Allow all CORS for dev
+                // Allow all CORS for dev, with credentials so the SSO link cookie sticks
+                .layer(CorsLayer::very_permissive())
                 .layer(layer)
         );
 
@@ -65,7 +157,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     info!("Listening on {}", addr);
 
     let listener = tokio::net::TcpListener::bind(addr).await?;
-    axum::serve(listener, app).await?;
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await?;
 
     Ok(())
 }
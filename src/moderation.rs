@@ -0,0 +1,247 @@
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use socketioxide::operators::BroadcastOperators;
+use crate::auth::AuthUser;
+use crate::roles::{Permission, Role};
+use crate::state::AppState;
+use crate::types::{Report, Room, RoomBan, User};
+
+// Bans can be given for at most a year; longer ones are just permanent
+const MAX_BAN_DURATION: i64 = 365 * 24 * 60 * 60;
+
+#[derive(Deserialize, Default)]
+pub struct BanOptions {
+    pub reason: Option<String>,
+    #[serde(rename = "expiresIn")]
+    pub expires_in: Option<i64>, // seconds; without it the ban is permanent
+}
+
+#[derive(Deserialize, Default)]
+pub struct MuteOptions {
+    pub chat: Option<bool>,
+    pub voice: Option<bool>,
+}
+
+#[derive(Deserialize)]
+pub struct ReportFilter {
+    status: Option<String>,
+}
+
+fn target_role(room: &Room, user_id: &str) -> Role {
+    // Guests have no account, so no stored role either
+    room.role_of(Some(user_id).filter(|id| !id.starts_with("guest-")))
+}
+
+/// Checks that the account `actor` may moderate the user `target` in the room:
+/// it needs moderator permissions there and a role above the target's.
+pub fn check(state: &AppState, room_id: &str, actor: &str, target: &str) -> Result<Room, (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if room.owner_id.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Rooms without an owner have no moderators"));
+    }
+    let actor_role = room.role_of(Some(actor));
+    if !actor_role.can(Permission::Moderate) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to moderate this room"));
+    }
+    if target_role(&room, target) >= actor_role {
+        return Err((StatusCode::FORBIDDEN, "Can only moderate users below your own role"));
+    }
+    Ok(room)
+}
+
+/// Takes every socket of a user out of the room, telling them why with
+/// `event`, and tells the room the user left. `within` addresses Socket.IO
+/// rooms, from either a socket or the server.
+pub fn remove_from_room(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    room_id: &str,
+    user_id: &str,
+    event: &'static str,
+    details: serde_json::Value,
+) {
+    let user_room = format!("user:{}", user_id);
+    let _ = within(user_room.clone()).emit(event, details);
+    let _ = within(user_room).leave(room_id.to_string());
+
+    if state.evict_user(room_id, user_id) {
+        state.buffer_event(room_id, "user_left", &user_id);
+        let _ = within(room_id.to_string()).emit("user_left", user_id);
+    }
+}
+
+pub fn kick(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    room_id: &str,
+    actor: &str,
+    target: &str,
+    reason: Option<String>,
+) -> Result<(), (StatusCode, &'static str)> {
+    check(state, room_id, actor, target)?;
+    remove_from_room(state, within, room_id, target, "kicked", json!({ "roomId": room_id, "reason": reason }));
+    Ok(())
+}
+
+/// Bans a user (an account or a guest id) from the room and takes them out
+/// of it. They can't rejoin until the ban expires or is lifted.
+pub async fn ban(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    room_id: &str,
+    actor: &str,
+    target: &str,
+    options: BanOptions,
+) -> Result<RoomBan, (StatusCode, &'static str)> {
+    check(state, room_id, actor, target)?;
+    if options.expires_in.is_some_and(|secs| !(1..=MAX_BAN_DURATION).contains(&secs)) {
+        return Err((StatusCode::BAD_REQUEST, "expiresIn must be between 1 second and a year"));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let ban = RoomBan {
+        user_id: target.to_string(),
+        banned_by: actor.to_string(),
+        reason: options.reason.filter(|r| !r.trim().is_empty()),
+        expires_at: options.expires_in.map(|secs| now + secs),
+        created_at: now,
+    };
+    state.db.ban_user(room_id, &ban).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to ban user"))?;
+
+    remove_from_room(state, within, room_id, target, "banned", json!({
+        "roomId": room_id,
+        "reason": ban.reason,
+        "expiresAt": ban.expires_at,
+    }));
+    Ok(ban)
+}
+
+/// Sets the chat and/or voice mute of a user in the room. Mutes outlast
+/// leaving the room; flags left out stay as they are.
+pub async fn mute(
+    state: &AppState,
+    within: impl Fn(String) -> BroadcastOperators,
+    room_id: &str,
+    actor: &str,
+    target: &str,
+    options: MuteOptions,
+) -> Result<(), (StatusCode, &'static str)> {
+    check(state, room_id, actor, target)?;
+
+    let (chat, voice) = state.db.get_mutes(room_id, target).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to mute user"))?;
+    let chat = options.chat.unwrap_or(chat);
+    let voice = options.voice.unwrap_or(voice);
+    state.db.set_mutes(room_id, target, chat, voice).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to mute user"))?;
+
+    if let Some(user) = state.set_user_mutes(room_id, target, chat, voice) {
+        let update = mute_update(room_id, &user);
+        state.buffer_event(room_id, "user_muted", &update);
+        let _ = within(room_id.to_string()).emit("user_muted", update);
+    }
+    Ok(())
+}
+
+fn mute_update(room_id: &str, user: &User) -> serde_json::Value {
+    json!({
+        "roomId": room_id,
+        "userId": user.id,
+        "chatMuted": user.chat_muted,
+        "voiceMuted": user.voice_muted,
+    })
+}
+
+/// Files a report into the moderation queue.
+pub async fn report(state: &AppState, room_id: &str, reporter: &str, target: &str, reason: &str) -> Result<Report, (StatusCode, &'static str)> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.chars().count() > 1000 {
+        return Err((StatusCode::BAD_REQUEST, "Reason must be 1 to 1000 characters"));
+    }
+
+    let report = Report {
+        id: uuid::Uuid::new_v4().to_string(),
+        room_id: room_id.to_string(),
+        reporter_id: reporter.to_string(),
+        target_id: target.to_string(),
+        reason: reason.to_string(),
+        status: "open".to_string(),
+        created_at: chrono::Utc::now().timestamp(),
+        resolved_by: None,
+        resolved_at: None,
+    };
+    state.db.create_report(&report).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to file report"))?;
+    Ok(report)
+}
+
+/// Current bans of a room, for its moderators.
+pub async fn list_bans(State(state): State<AppState>, user: AuthUser, Path(room_id): Path<String>) -> impl IntoResponse {
+    match state.get_room(&room_id) {
+        Some(room) if room.allows(Some(&user.account_id), Permission::Moderate) => {}
+        Some(_) => return (StatusCode::FORBIDDEN, "Not allowed to moderate this room").into_response(),
+        None => return (StatusCode::NOT_FOUND, "Room not found").into_response(),
+    }
+
+    match state.db.get_bans(&room_id, chrono::Utc::now().timestamp()).await {
+        Ok(bans) => Json(bans).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load bans").into_response(),
+    }
+}
+
+pub async fn unban(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((room_id, user_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if let Err(error) = check(&state, &room_id, &user.account_id, &user_id) {
+        return error.into_response();
+    }
+
+    match state.db.unban_user(&room_id, &user_id).await {
+        Ok(true) => (StatusCode::OK, "Ban lifted").into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "User is not banned").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to lift ban").into_response(),
+    }
+}
+
+/// The moderation queue, for the accounts in `ADMIN_ACCOUNT_IDS`.
+pub async fn list_reports(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Query(filter): Query<ReportFilter>,
+) -> impl IntoResponse {
+    if !state.is_admin(&user.account_id) {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+
+    match state.db.get_reports(filter.status.as_deref()).await {
+        Ok(reports) => Json(reports).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load reports").into_response(),
+    }
+}
+
+pub async fn resolve_report(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(report_id): Path<String>,
+) -> impl IntoResponse {
+    if !state.is_admin(&user.account_id) {
+        return (StatusCode::FORBIDDEN, "Admins only").into_response();
+    }
+
+    match state.db.resolve_report(&report_id, &user.account_id, chrono::Utc::now().timestamp()).await {
+        Ok(true) => (StatusCode::OK, "Report resolved").into_response(),
+        Ok(false) => (StatusCode::NOT_FOUND, "No open report with that id").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to resolve report").into_response(),
+    }
+}
@@ -0,0 +1,198 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use socketioxide::SocketIo;
+use crate::api;
+use crate::auth::AuthUser;
+use crate::state::AppState;
+
+/// A user's standing in one room. Owners come from the room itself; the other
+/// roles are stored per account, and anyone without one is a member (or a
+/// guest, when not logged in).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Guest,
+    Member,
+    Moderator,
+    Owner,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Permission {
+    Chat,
+    React,
+    Draw,
+    ShareEmbed,
+    EditObjects,
+    RemoveObjects,
+    ChangeSettings,
+    ManageRoles,
+    Report,
+    Moderate,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Guest => "guest",
+            Role::Member => "member",
+            Role::Moderator => "moderator",
+            Role::Owner => "owner",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "guest" => Some(Role::Guest),
+            "member" => Some(Role::Member),
+            "moderator" => Some(Role::Moderator),
+            "owner" => Some(Role::Owner),
+            _ => None,
+        }
+    }
+
+    /// The permission matrix: each permission is granted from some role up.
+    pub fn can(&self, permission: Permission) -> bool {
+        let needed = match permission {
+            Permission::Chat | Permission::React => Role::Guest,
+            Permission::Draw | Permission::ShareEmbed | Permission::EditObjects | Permission::Report => Role::Member,
+            Permission::RemoveObjects | Permission::ChangeSettings | Permission::ManageRoles | Permission::Moderate => Role::Moderator,
+        };
+        *self >= needed
+    }
+}
+
+#[derive(Deserialize)]
+pub struct RolePayload {
+    role: Role,
+}
+
+/// Gives `target` a role in the room, or takes their stored role away with
+/// `None`. Callers can only hand out roles below their own, to accounts
+/// currently below them, so moderators manage members and guests and only
+/// the owner manages moderators.
+pub async fn assign(
+    state: &AppState,
+    actor: &str,
+    room_id: &str,
+    target: &str,
+    role: Option<Role>,
+) -> Result<(), (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if room.owner_id.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Rooms without an owner have no roles"));
+    }
+
+    let actor_role = room.role_of(Some(actor));
+    if !actor_role.can(Permission::ManageRoles) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to manage roles in this room"));
+    }
+    if role == Some(Role::Owner) {
+        return Err((StatusCode::BAD_REQUEST, "Ownership can't be granted"));
+    }
+    if room.role_of(Some(target)) >= actor_role || role.is_some_and(|r| r >= actor_role) {
+        return Err((StatusCode::FORBIDDEN, "Can only manage roles below your own"));
+    }
+
+    match state.db.get_profile(target).await {
+        Ok(Some(_)) => {}
+        Ok(None) => return Err((StatusCode::NOT_FOUND, "Account not found")),
+        Err(_) => return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to update role")),
+    }
+    state.db.set_room_role(room_id, target, role).await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "Failed to update role"))?;
+    state.set_room_role(room_id, target, role);
+    Ok(())
+}
+
+/// What to tell a room after a role changed.
+pub fn role_update(state: &AppState, room_id: &str, account_id: &str) -> serde_json::Value {
+    let role = state.get_room(room_id).map(|r| r.role_of(Some(account_id)));
+    json!({ "roomId": room_id, "accountId": account_id, "role": role })
+}
+
+/// Who holds which role, for anyone who can read the room.
+pub async fn list(State(state): State<AppState>, user: AuthUser, Path(room_id): Path<String>) -> impl IntoResponse {
+    if let Err(error) = api::readable_room(&state, &room_id, &user).await {
+        return error.into_response();
+    }
+    match state.get_room(&room_id) {
+        Some(room) => Json(json!({ "ownerId": room.owner_id, "roles": room.roles })).into_response(),
+        None => (StatusCode::NOT_FOUND, "Room not found").into_response(),
+    }
+}
+
+pub async fn grant(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path((room_id, account_id)): Path<(String, String)>,
+    Json(payload): Json<RolePayload>,
+) -> impl IntoResponse {
+    change(&state, &io, &user, &room_id, &account_id, Some(payload.role)).await
+}
+
+pub async fn revoke(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path((room_id, account_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    change(&state, &io, &user, &room_id, &account_id, None).await
+}
+
+async fn change(
+    state: &AppState,
+    io: &SocketIo,
+    user: &AuthUser,
+    room_id: &str,
+    account_id: &str,
+    role: Option<Role>,
+) -> axum::response::Response {
+    if let Err(error) = assign(state, &user.account_id, room_id, account_id, role).await {
+        return error.into_response();
+    }
+
+    let update = role_update(state, room_id, account_id);
+    state.buffer_event(room_id, "role_updated", &update);
+    let _ = io.within(room_id.to_string()).emit("role_updated", update.clone());
+    Json(update).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn each_role_gets_its_permissions_and_those_below() {
+        use Permission::*;
+        let granted = |role: Role| -> Vec<Permission> {
+            [Chat, React, Draw, ShareEmbed, EditObjects, RemoveObjects, ChangeSettings, ManageRoles, Report, Moderate]
+                .into_iter()
+                .filter(|p| role.can(*p))
+                .collect()
+        };
+
+        assert_eq!(granted(Role::Guest), [Chat, React]);
+        assert_eq!(granted(Role::Member), [Chat, React, Draw, ShareEmbed, EditObjects, Report]);
+        assert_eq!(granted(Role::Moderator).len(), 10);
+        assert_eq!(granted(Role::Owner).len(), 10);
+    }
+
+    #[test]
+    fn roles_parse_from_their_names() {
+        for role in [Role::Guest, Role::Member, Role::Moderator, Role::Owner] {
+            assert_eq!(Role::parse(role.as_str()), Some(role));
+        }
+        assert_eq!(Role::parse("admin"), None);
+    }
+}
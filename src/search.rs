@@ -0,0 +1,128 @@
+use axum::{
+    extract::{Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use crate::api;
+use crate::auth::AuthUser;
+use crate::db::{self, MessageSearch};
+use crate::state::AppState;
+
+const DEFAULT_PAGE_SIZE: usize = 20;
+const MAX_PAGE_SIZE: usize = 100;
+const MAX_TERMS: usize = 16;
+
+#[derive(Deserialize, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchSort {
+    #[default]
+    Relevance,
+    Recent,
+}
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    q: String,
+    #[serde(rename = "roomId")]
+    room_id: Option<String>, // all the rooms the caller can read without it
+    author: Option<String>,  // user or account id
+    from: Option<i64>,       // timestamps (ms), `to` exclusive
+    to: Option<i64>,
+    #[serde(default)]
+    sort: SearchSort,
+    limit: Option<usize>,
+    offset: Option<usize>,
+}
+
+/// Turns what the user typed into an FTS5 query matching messages with all
+/// of its words. Words are quoted, so nothing typed is taken as query syntax;
+/// a trailing `*` still matches by prefix.
+fn match_query(q: &str) -> Option<String> {
+    let terms: Vec<String> = q.split_whitespace()
+        .filter_map(|word| {
+            let (word, prefix) = match word.strip_suffix('*') {
+                Some(word) => (word, "*"),
+                None => (word, ""),
+            };
+            (!word.is_empty()).then(|| format!("\"{}\"{}", word.replace('"', "\"\""), prefix))
+        })
+        .take(MAX_TERMS)
+        .collect();
+    (!terms.is_empty()).then(|| terms.join(" "))
+}
+
+/// Escapes a snippet for HTML and wraps the matches in `<mark>`.
+fn highlight(snippet: &str) -> String {
+    snippet
+        .replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+        .replace(db::SNIPPET_START, "<mark>")
+        .replace(db::SNIPPET_END, "</mark>")
+}
+
+/// Searches the chat history of the rooms the caller can read, or of just
+/// `roomId`. Deleted messages are never found.
+pub async fn search_messages(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Query(query): Query<SearchQuery>,
+) -> impl IntoResponse {
+    let Some(match_query) = match_query(&query.q) else {
+        return (StatusCode::BAD_REQUEST, "Search query can't be empty").into_response();
+    };
+    if let (Some(from), Some(to)) = (query.from, query.to) {
+        if from >= to {
+            return (StatusCode::BAD_REQUEST, "from must be before to").into_response();
+        }
+    }
+
+    let room_ids: Vec<String> = match &query.room_id {
+        Some(room_id) => match api::readable_room(&state, room_id, &user).await {
+            Ok(()) => vec![room_id.clone()],
+            Err(error) => return error.into_response(),
+        },
+        None => {
+            let banned = match state.db.get_banned_room_ids(&user.account_id, chrono::Utc::now().timestamp()).await {
+                Ok(banned) => banned,
+                Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to search messages").into_response(),
+            };
+            state.rooms.iter()
+                .filter(|room| !banned.contains(&room.id))
+                .filter(|room| state.check_room_access(room, Some(&user.account_id), None).is_ok())
+                .map(|room| room.id.clone())
+                .collect()
+        }
+    };
+
+    let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE).clamp(1, MAX_PAGE_SIZE);
+    let search = MessageSearch {
+        query: &match_query,
+        room_ids: &room_ids,
+        author: query.author.as_deref(),
+        from: query.from,
+        to: query.to,
+        limit: limit as i64 + 1,
+        offset: query.offset.unwrap_or(0) as i64,
+        newest_first: query.sort == SearchSort::Recent,
+    };
+    let mut found = match state.db.search_messages(&search).await {
+        Ok(found) => found,
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to search messages").into_response(),
+    };
+    let has_more = found.len() > limit;
+    found.truncate(limit);
+
+    let results: Vec<_> = found.into_iter().map(|(room_id, message, snippet)| json!({
+        "roomName": state.get_room(&room_id).map(|r| r.name),
+        "roomId": room_id,
+        "message": message,
+        "snippet": highlight(&snippet),
+    })).collect();
+    Json(json!({ "results": results, "hasMore": has_more })).into_response()
+}
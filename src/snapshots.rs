@@ -0,0 +1,257 @@
+use std::time::Duration;
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use socketioxide::SocketIo;
+use tracing::warn;
+use crate::auth::AuthUser;
+use crate::lobby;
+use crate::roles::Permission;
+use crate::state::AppState;
+use crate::types::{Room, RoomSnapshot};
+
+// Automatic snapshots kept per room; manual ones stay until the room goes
+const MAX_AUTO_SNAPSHOTS: i64 = 50;
+
+#[derive(Deserialize)]
+pub struct CreateSnapshot {
+    label: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct DiffQuery {
+    against: Option<String>, // another snapshot id; the room as it is now without it
+}
+
+fn capture(room: &Room, label: Option<String>, created_by: Option<String>) -> RoomSnapshot {
+    RoomSnapshot {
+        id: uuid::Uuid::new_v4().to_string(),
+        room_id: room.id.clone(),
+        label,
+        created_by,
+        background: room.background.clone(),
+        max_users: room.max_users,
+        knock: room.knock,
+        objects: room.objects.clone(),
+        created_at: chrono::Utc::now().timestamp(),
+    }
+}
+
+fn same_content(a: &RoomSnapshot, b: &RoomSnapshot) -> bool {
+    a.objects == b.objects && a.background == b.background && a.max_users == b.max_users && a.knock == b.knock
+}
+
+/// Snapshots are for whoever may change the room's settings.
+fn managed_room(state: &AppState, room_id: &str, user: &AuthUser) -> Result<Room, (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if !room.role_of(Some(&user.account_id)).can(Permission::ChangeSettings) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to manage this room's snapshots"));
+    }
+    Ok(room)
+}
+
+async fn find_snapshot(state: &AppState, room_id: &str, snapshot_id: &str) -> Result<RoomSnapshot, (StatusCode, &'static str)> {
+    match state.db.get_snapshot(room_id, snapshot_id).await {
+        Ok(Some(snapshot)) => Ok(snapshot),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Snapshot not found")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load snapshot")),
+    }
+}
+
+/// Takes a snapshot of every room whose objects or settings changed since
+/// its last one, every `interval`.
+pub async fn run_periodic(state: AppState, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let rooms: Vec<Room> = state.rooms.iter().map(|r| r.clone()).collect();
+        for room in rooms {
+            let snapshot = capture(&room, None, None);
+            let changed = match state.db.latest_snapshot(&room.id).await {
+                Ok(Some(latest)) => !same_content(&latest, &snapshot),
+                // Nothing worth keeping in a room that never had anything
+                Ok(None) => !snapshot.objects.is_empty() || snapshot.background.is_some(),
+                Err(_) => false,
+            };
+            if !changed {
+                continue;
+            }
+            if let Err(e) = state.db.save_snapshot(&snapshot).await {
+                warn!("Failed to snapshot room {}: {}", room.id, e);
+                continue;
+            }
+            if let Err(e) = state.db.prune_auto_snapshots(&room.id, MAX_AUTO_SNAPSHOTS).await {
+                warn!("Failed to prune snapshots of room {}: {}", room.id, e);
+            }
+        }
+    }
+}
+
+/// Snapshots of a room, newest first, without their objects.
+pub async fn list(State(state): State<AppState>, user: AuthUser, Path(room_id): Path<String>) -> impl IntoResponse {
+    if let Err(error) = managed_room(&state, &room_id, &user) {
+        return error.into_response();
+    }
+
+    match state.db.get_snapshots(&room_id).await {
+        Ok(snapshots) => Json(snapshots.iter().map(|s| json!({
+            "id": s.id,
+            "label": s.label,
+            "createdBy": s.created_by,
+            "createdAt": s.created_at,
+            "objectCount": s.objects.len(),
+        })).collect::<Vec<_>>()).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load snapshots").into_response(),
+    }
+}
+
+pub async fn create(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    payload: Option<Json<CreateSnapshot>>,
+) -> impl IntoResponse {
+    let room = match managed_room(&state, &room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    let label = payload.and_then(|Json(p)| p.label)
+        .map(|l| l.trim().to_string())
+        .filter(|l| !l.is_empty());
+    if label.as_ref().is_some_and(|l| l.chars().count() > 64) {
+        return (StatusCode::BAD_REQUEST, "Label must be at most 64 characters").into_response();
+    }
+
+    let snapshot = capture(&room, label, Some(user.account_id));
+    match state.db.save_snapshot(&snapshot).await {
+        Ok(_) => (StatusCode::CREATED, Json(snapshot)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to take snapshot").into_response(),
+    }
+}
+
+pub async fn get(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((room_id, snapshot_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    if let Err(error) = managed_room(&state, &room_id, &user) {
+        return error.into_response();
+    }
+    match find_snapshot(&state, &room_id, &snapshot_id).await {
+        Ok(snapshot) => Json(snapshot).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+/// What changed from the snapshot to another one, or to the room as it is now.
+pub async fn diff(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Path((room_id, snapshot_id)): Path<(String, String)>,
+    Query(query): Query<DiffQuery>,
+) -> impl IntoResponse {
+    let room = match managed_room(&state, &room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    let from = match find_snapshot(&state, &room_id, &snapshot_id).await {
+        Ok(snapshot) => snapshot,
+        Err(error) => return error.into_response(),
+    };
+    // `to` is null when comparing against the room as it is now
+    let (to, to_id) = match query.against {
+        Some(against) => match find_snapshot(&state, &room_id, &against).await {
+            Ok(snapshot) => (snapshot, Some(against)),
+            Err(error) => return error.into_response(),
+        },
+        None => (capture(&room, None, None), None),
+    };
+
+    let added: Vec<_> = to.objects.iter()
+        .filter(|o| !from.objects.iter().any(|f| f.id == o.id))
+        .collect();
+    let removed: Vec<_> = from.objects.iter()
+        .filter(|o| !to.objects.iter().any(|t| t.id == o.id))
+        .collect();
+    let changed: Vec<_> = from.objects.iter()
+        .filter_map(|f| to.objects.iter().find(|t| t.id == f.id && *t != f).map(|t| json!({ "before": f, "after": t })))
+        .collect();
+
+    let mut settings = serde_json::Map::new();
+    if from.background != to.background {
+        settings.insert("background".into(), json!({ "before": from.background, "after": to.background }));
+    }
+    if from.max_users != to.max_users {
+        settings.insert("maxUsers".into(), json!({ "before": from.max_users, "after": to.max_users }));
+    }
+    if from.knock != to.knock {
+        settings.insert("knock".into(), json!({ "before": from.knock, "after": to.knock }));
+    }
+
+    Json(json!({
+        "from": from.id,
+        "to": to_id,
+        "added": added,
+        "removed": removed,
+        "changed": changed,
+        "settings": settings,
+    })).into_response()
+}
+
+/// Puts the room back to a snapshot and sends the restored state to everyone
+/// in it. The state being replaced is snapshotted first, so a restore can be
+/// undone.
+pub async fn restore(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path((room_id, snapshot_id)): Path<(String, String)>,
+) -> impl IntoResponse {
+    let room = match managed_room(&state, &room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    let snapshot = match find_snapshot(&state, &room_id, &snapshot_id).await {
+        Ok(snapshot) => snapshot,
+        Err(error) => return error.into_response(),
+    };
+
+    let backup = capture(&room, Some("Before restore".to_string()), Some(user.account_id.clone()));
+    if state.db.save_snapshot(&backup).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to restore snapshot").into_response();
+    }
+
+    let mut restored = room;
+    restored.objects = snapshot.objects;
+    restored.background = snapshot.background;
+    restored.max_users = snapshot.max_users;
+    restored.knock = snapshot.knock;
+    if state.db.replace_room_objects(&room_id, &restored.objects).await.is_err()
+        || state.db.save_room_settings(&restored).await.is_err()
+    {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to restore snapshot").into_response();
+    }
+    let restored = state.restore_room(&restored).unwrap_or(restored);
+
+    let update = json!({
+        "roomId": room_id,
+        "snapshotId": snapshot_id,
+        "objects": restored.objects,
+        "background": restored.background,
+        "maxUsers": restored.max_users,
+        "knock": restored.knock,
+    });
+    state.buffer_event(&room_id, "room_restored", &update);
+    let _ = io.within(room_id.clone()).emit("room_restored", update);
+    if !restored.knock {
+        lobby::admit_all(&state, |room: String| io.within(room), &room_id);
+    }
+
+    Json(json!({ "restored": snapshot_id, "backup": backup.id })).into_response()
+}
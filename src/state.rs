@@ -1,16 +1,41 @@
-use dashmap::DashMap;
+use dashmap::{mapref::entry::Entry, DashMap};
+use serde::Serialize;
 use std::sync::Arc;
-use crate::types::{Room, User};
-use crate::db::Db;
+use tracing::warn;
+use std::time::{Duration, Instant};
+use crate::types::{Identity, Knock, ParkedSession, Room, SocketSession, User, Visibility};
+use crate::auth;
+use crate::db::{self, Db};
+use crate::keys::KeyRing;
+use crate::roles::Role;
+use std::collections::{HashMap, HashSet};
+use crate::oidc::OidcClient;
+use crate::throttle::LoginThrottle;
+
+// Past this many events a parked socket gets a fresh room_state instead of a replay
+const MAX_BUFFERED_EVENTS: usize = 500;
 
 #[derive(Clone)]
 pub struct AppState {
     pub rooms: Arc<DashMap<String, Room>>,
+    pub identities: Arc<DashMap<String, Identity>>, // socket id -> authenticated account
+    pub sockets: Arc<DashMap<String, SocketSession>>, // socket id -> stable user id and current room
+    pub parked: Arc<DashMap<String, ParkedSession>>, // recovery id -> socket that dropped out of a room
+    pub lobbies: Arc<DashMap<String, Vec<Knock>>>, // room id -> users waiting to be let in, in order
+    pub admissions: Arc<DashMap<(String, String), Instant>>, // (room id, user id) -> admitted until
+    pub reconnect_grace: Duration,
+    pub snapshot_interval: Option<Duration>, // None turns automatic snapshots off
+    pub admins: Arc<HashSet<String>>, // account ids allowed into the admin API
     pub db: Db,
+    pub keys: Arc<KeyRing>,
+    pub account_throttle: Arc<LoginThrottle>,
+    pub ip_throttle: Arc<LoginThrottle>,
+    pub room_throttle: Arc<LoginThrottle>, // wrong room passwords, per room and user
+    pub oidc: Option<Arc<OidcClient>>,
 }
 
 impl AppState {
-    pub async fn new(db_url: &str) -> Result<Self, sqlx::Error> {
+    pub async fn new(db_url: &str, keys: KeyRing, oidc: Option<OidcClient>) -> Result<Self, sqlx::Error> {
         let db = Db::new(db_url).await?;
         let rooms = DashMap::new();
         
@@ -22,68 +47,201 @@ impl AppState {
 
         Ok(Self {
             rooms: Arc::new(rooms),
+            identities: Arc::new(DashMap::new()),
+            sockets: Arc::new(DashMap::new()),
+            parked: Arc::new(DashMap::new()),
+            lobbies: Arc::new(DashMap::new()),
+            admissions: Arc::new(DashMap::new()),
+            reconnect_grace: Duration::from_secs(
+                std::env::var("RECONNECT_GRACE_SECONDS").ok().and_then(|v| v.parse().ok()).unwrap_or(30),
+            ),
+            snapshot_interval: match std::env::var("SNAPSHOT_INTERVAL_SECONDS").ok().and_then(|v| v.parse().ok()) {
+                Some(0) => None,
+                Some(secs) => Some(Duration::from_secs(secs)),
+                None => Some(Duration::from_secs(300)),
+            },
+            admins: Arc::new(
+                std::env::var("ADMIN_ACCOUNT_IDS").unwrap_or_default()
+                    .split(',')
+                    .map(|id| id.trim().to_string())
+                    .filter(|id| !id.is_empty())
+                    .collect(),
+            ),
             db,
+            keys: Arc::new(keys),
+            account_throttle: Arc::new(LoginThrottle::new(5)),
+            ip_throttle: Arc::new(LoginThrottle::new(20)),
+            room_throttle: Arc::new(LoginThrottle::new(5)),
+            oidc: oidc.map(Arc::new),
         })
     }
 
+    /// Keyed on account ids rather than usernames, since a name frees up
+    /// again when its account is deleted and anyone could register it.
+    pub fn is_admin(&self, account_id: &str) -> bool {
+        self.admins.contains(account_id)
+    }
+
+    pub fn get_identity(&self, socket_id: &str) -> Option<Identity> {
+        self.identities.get(socket_id).map(|i| i.clone())
+    }
+
+    pub fn get_session(&self, socket_id: &str) -> Option<SocketSession> {
+        self.sockets.get(socket_id).map(|s| s.clone())
+    }
+
+    pub fn set_socket_room(&self, socket_id: &str, room_id: Option<String>) {
+        if let Some(mut session) = self.sockets.get_mut(socket_id) {
+            session.room_id = room_id;
+        }
+    }
+
+    /// Whether `user_id` still has a socket other than `except_socket` in the room,
+    /// in which case its presence there has to stay.
+    pub fn user_present_elsewhere(&self, user_id: &str, room_id: &str, except_socket: &str) -> bool {
+        self.sockets.iter().any(|s| {
+            s.key() != except_socket && s.user_id == user_id && s.room_id.as_deref() == Some(room_id)
+        })
+    }
+
+    pub fn user_parked_in(&self, user_id: &str, room_id: &str) -> bool {
+        self.parked.iter().any(|p| p.user_id == user_id && p.room_id == room_id)
+    }
+
+    pub fn park_socket(&self, recovery_id: &str, user_id: &str, room_id: &str) {
+        self.parked.insert(recovery_id.to_string(), ParkedSession {
+            user_id: user_id.to_string(),
+            room_id: room_id.to_string(),
+            expires_at: Instant::now() + self.reconnect_grace,
+            events: Vec::new(),
+            overflowed: false,
+        });
+    }
+
+    /// Hands back a parked session to resume, if it belongs to `user_id` and can
+    /// still be replayed. Anything else is left for the grace period to clean up.
+    pub fn take_parked(&self, recovery_id: &str, user_id: &str) -> Option<ParkedSession> {
+        self.parked.remove_if(recovery_id, |_, p| {
+            p.user_id == user_id && !p.overflowed && p.expires_at > Instant::now()
+        }).map(|(_, p)| p)
+    }
+
+    /// Drops a parked session whose grace period is over. One that was resumed
+    /// and parked again in the meantime has a later deadline and stays.
+    pub fn expire_parked(&self, recovery_id: &str) -> Option<ParkedSession> {
+        self.parked.remove_if(recovery_id, |_, p| p.expires_at <= Instant::now())
+            .map(|(_, p)| p)
+    }
+
+    /// Keeps a copy of a room event for every socket parked in that room.
+    pub fn buffer_event<T: Serialize>(&self, room_id: &str, event: &str, data: &T) {
+        let mut value = None;
+        for mut parked in self.parked.iter_mut().filter(|p| p.room_id == room_id) {
+            if parked.events.len() >= MAX_BUFFERED_EVENTS {
+                parked.overflowed = true;
+                parked.events.clear();
+            }
+            if parked.overflowed {
+                continue;
+            }
+            let value = value.get_or_insert_with(|| serde_json::to_value(data).unwrap_or_default());
+            parked.events.push((event.to_string(), value.clone()));
+        }
+    }
+
+    /// Checks whether someone may join a room, returning the `join_error`
+    /// reason if not. Owners, accounts with a role in the room (which
+    /// everyone who joined with an invite has) and invite holders always get
+    /// in; for a `password_required` room see `check_room_password`.
+    pub fn check_room_access(&self, room: &Room, account_id: Option<&str>, invite: Option<&str>) -> Result<(), &'static str> {
+        if account_id.is_some() && room.owner_id.as_deref() == account_id {
+            return Ok(());
+        }
+        if account_id.is_some_and(|id| room.roles.contains_key(id)) {
+            return Ok(());
+        }
+        if invite.is_some_and(|token| auth::verify_invite_token(&self.keys, token, &room.id)) {
+            return Ok(());
+        }
+        if room.visibility == Visibility::Private {
+            return Err("invite_required");
+        }
+        match room.password_hash {
+            Some(_) => Err("password_required"),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks a room password, locking the user out of the room for a while
+    /// after repeated wrong guesses.
+    pub async fn check_room_password(&self, room: &Room, user_id: &str, password: &str) -> Result<(), &'static str> {
+        let Some(hash) = room.password_hash.clone() else {
+            return Ok(());
+        };
+        let key = format!("{}:{}", room.id, user_id);
+        if self.room_throttle.check(&key).is_some() {
+            return Err("too_many_attempts");
+        }
+
+        let password = password.to_string();
+        let matches = tokio::task::spawn_blocking(move || bcrypt::verify(password, &hash).unwrap_or(false))
+            .await
+            .unwrap_or(false);
+        if matches {
+            self.room_throttle.reset(&key);
+            Ok(())
+        } else {
+            self.room_throttle.record_failure(&key);
+            Err("wrong_password")
+        }
+    }
+
     pub fn get_room(&self, room_id: &str) -> Option<Room> {
         self.rooms.get(room_id).map(|r| r.clone())
     }
 
-    pub fn add_user_to_room(&self, room_id: String, user: User) {
-        self.rooms.entry(room_id.clone())
-            .or_insert_with(|| Room {
+    /// Returns false if the user was already present (another tab, or a reconnect).
+    pub fn add_user_to_room(&self, room_id: String, user: User) -> bool {
+        // Joining a room nobody created yet creates it, without an owner
+        if let Entry::Vacant(entry) = self.rooms.entry(room_id.clone()) {
+            let room = entry.insert(Room {
                 id: room_id.clone(),
                 name: format!("Room {}", room_id),
                 users: Vec::new(),
                 objects: Vec::new(),
                 background: None,
+                owner_id: None,
+                visibility: Visibility::Public,
+                password_hash: None,
+                roles: HashMap::new(),
+                max_users: None,
+                knock: false,
+                description: None,
+                category: None,
+                tags: Vec::new(),
+            }).clone();
+
+            let db = self.db.clone();
+            tokio::spawn(async move {
+                let _ = db.insert_room(&room).await;
             });
-        
-        // Ensure room exists in DB (upsert)
-        // We do this outside the entry lock to avoid holding it too long, 
-        // but we need to know if we just created it. 
-        // For simplicity, just upsert always or check existence.
-        // Actually, entry API makes it hard to async await inside.
-        // Let's just fire and forget an upsert for the room itself.
-        let db = self.db.clone();
-        let rid = room_id.clone();
-        let rname = format!("Room {}", room_id);
-        tokio::spawn(async move {
-            // Minimal room struct for saving
-            let room = Room { id: rid, name: rname, users: vec![], objects: vec![], background: None };
-            let _ = db.save_room(&room).await;
-        });
+        }
 
         if let Some(mut room) = self.rooms.get_mut(&room_id) {
             // Check if user already exists to avoid duplicates
             if !room.users.iter().any(|u| u.id == user.id) {
                 room.users.push(user);
+                return true;
             }
         }
+        false
     }
 
-    pub fn remove_user(&self, user_id: &str) {
-        // This is a bit inefficient, iterating all rooms. 
-        // In a real DB scenario this would be a direct lookup.
-        // For in-memory with DashMap, we iterate.
-        for mut room in self.rooms.iter_mut() {
-            if let Some(pos) = room.users.iter().position(|u| u.id == user_id) {
-                room.users.remove(pos);
-                // If room empty, maybe remove? Keeping it simple for now.
-                return;
-            }
+    pub fn remove_user_from_room(&self, room_id: &str, user_id: &str) {
+        if let Some(mut room) = self.rooms.get_mut(room_id) {
+            room.users.retain(|u| u.id != user_id);
         }
     }
-    
-    pub fn get_user_room(&self, user_id: &str) -> Option<String> {
-        for room in self.rooms.iter() {
-            if room.users.iter().any(|u| u.id == user_id) {
-                return Some(room.id.clone());
-            }
-        }
-        None
-    }
 
     pub fn get_user(&self, room_id: &str, user_id: &str) -> Option<User> {
         if let Some(room) = self.rooms.get(room_id) {
@@ -92,38 +250,198 @@ impl AppState {
         None
     }
 
-    pub fn update_user_position(&self, user_id: &str, x: f64, y: f64) -> Option<String> {
-        for mut room in self.rooms.iter_mut() {
-            if let Some(user) = room.users.iter_mut().find(|u| u.id == user_id) {
-                user.x = x;
-                user.y = y;
-                return Some(room.id.clone());
+    pub fn update_user_position(&self, room_id: &str, user_id: &str, x: f64, y: f64) -> Option<User> {
+        let mut room = self.rooms.get_mut(room_id)?;
+        let user = room.users.iter_mut().find(|u| u.id == user_id)?;
+        user.x = x;
+        user.y = y;
+        Some(user.clone())
+    }
+
+    /// Returns whether the flag actually changed.
+    pub fn set_user_reconnecting(&self, room_id: &str, user_id: &str, reconnecting: bool) -> bool {
+        let Some(mut room) = self.rooms.get_mut(room_id) else {
+            return false;
+        };
+        match room.users.iter_mut().find(|u| u.id == user_id) {
+            Some(user) if user.reconnecting != reconnecting => {
+                user.reconnecting = reconnecting;
+                true
             }
+            _ => false,
         }
-        None
     }
 
-    pub fn update_user_details(&self, user_id: &str, name: Option<String>, color: Option<String>) -> Option<(String, User)> {
+    pub fn set_user_mutes(&self, room_id: &str, user_id: &str, chat: bool, voice: bool) -> Option<User> {
+        let mut room = self.rooms.get_mut(room_id)?;
+        let user = room.users.iter_mut().find(|u| u.id == user_id)?;
+        user.chat_muted = chat;
+        user.voice_muted = voice;
+        Some(user.clone())
+    }
+
+    /// Forces a user out of a room: none of their sockets count as being in it
+    /// any more, parked ones included. Returns whether they were present.
+    pub fn evict_user(&self, room_id: &str, user_id: &str) -> bool {
+        for mut session in self.sockets.iter_mut() {
+            if session.user_id == user_id && session.room_id.as_deref() == Some(room_id) {
+                session.room_id = None;
+            }
+        }
+        self.parked.retain(|_, p| !(p.user_id == user_id && p.room_id == room_id));
+
+        let Some(mut room) = self.rooms.get_mut(room_id) else {
+            return false;
+        };
+        let before = room.users.len();
+        room.users.retain(|u| u.id != user_id);
+        room.users.len() != before
+    }
+
+    pub fn update_user_details(&self, room_id: &str, user_id: &str, name: Option<String>, color: Option<String>) -> Option<User> {
+        let mut room = self.rooms.get_mut(room_id)?;
+        let user = room.users.iter_mut().find(|u| u.id == user_id)?;
+        if let Some(n) = name { user.name = n; }
+        if let Some(c) = color { user.color = c; }
+        Some(user.clone())
+    }
+
+    /// Copies a room's name and access settings into the live room.
+    pub fn update_room(&self, updated: &Room) -> Option<Room> {
+        let mut room = self.rooms.get_mut(&updated.id)?;
+        room.name = updated.name.clone();
+        room.visibility = updated.visibility;
+        room.description = updated.description.clone();
+        room.category = updated.category.clone();
+        room.tags = updated.tags.clone();
+        room.password_hash = updated.password_hash.clone();
+        Some(room.clone())
+    }
+
+    /// Forgets a deleted room, including which sockets were in it.
+    pub fn remove_room(&self, room_id: &str) -> Option<Room> {
+        let (_, room) = self.rooms.remove(room_id)?;
+        for mut session in self.sockets.iter_mut() {
+            if session.room_id.as_deref() == Some(room_id) {
+                session.room_id = None;
+            }
+        }
+        self.parked.retain(|_, p| p.room_id != room_id);
+        self.lobbies.remove(room_id);
+        self.admissions.retain(|(room, _), _| room != room_id);
+        Some(room)
+    }
+
+    /// Forgets a deleted account's ownership and roles.
+    pub fn clear_room_owner(&self, account_id: &str) {
         for mut room in self.rooms.iter_mut() {
-            let room_id = room.id.clone();
-            if let Some(user) = room.users.iter_mut().find(|u| u.id == user_id) {
-                if let Some(n) = name { user.name = n; }
-                if let Some(c) = color { user.color = c; }
-                return Some((room_id, user.clone()));
+            if room.owner_id.as_deref() == Some(account_id) {
+                room.owner_id = None;
             }
+            room.roles.remove(account_id);
         }
-        None
     }
 
-    pub fn add_object(&self, room_id: String, object: crate::types::RoomObject) {
-        if let Some(mut room) = self.rooms.get_mut(&room_id) {
-            room.objects.push(object.clone());
-            // Async save (fire and forget for now, or spawn task)
-            let db = self.db.clone();
-            let rid = room_id.clone();
-            tokio::spawn(async move {
-                let _ = db.save_object(&rid, &object).await;
-            });
+    pub fn set_room_role(&self, room_id: &str, account_id: &str, role: Option<Role>) {
+        if let Some(mut room) = self.rooms.get_mut(room_id) {
+            match role {
+                Some(role) => room.roles.insert(account_id.to_string(), role),
+                None => room.roles.remove(account_id),
+            };
+        }
+    }
+
+    /// The role the socket acts with in `room_id`, provided it is in there.
+    pub fn socket_role(&self, socket_id: &str, room_id: &str) -> Option<Role> {
+        let session = self.get_session(socket_id)?;
+        if session.room_id.as_deref() != Some(room_id) {
+            return None;
+        }
+        let account_id = self.get_identity(socket_id).map(|i| i.account_id);
+        let room = self.rooms.get(room_id)?;
+        Some(room.role_of(account_id.as_deref()))
+    }
+
+    /// Puts the user in the room's lobby (or refreshes their spot, keeping
+    /// their place in the queue) and returns their 1-based position.
+    pub fn knock(&self, room_id: &str, knock: Knock) -> usize {
+        let mut queue = self.lobbies.entry(room_id.to_string()).or_default();
+        match queue.iter().position(|k| k.user_id == knock.user_id) {
+            Some(index) => {
+                queue[index].socket_id = knock.socket_id;
+                queue[index].name = knock.name;
+                index + 1
+            }
+            None => {
+                queue.push(knock);
+                queue.len()
+            }
+        }
+    }
+
+    pub fn lobby(&self, room_id: &str) -> Vec<Knock> {
+        self.lobbies.get(room_id).map(|q| q.clone()).unwrap_or_default()
+    }
+
+    pub fn take_knock(&self, room_id: &str, user_id: &str) -> Option<Knock> {
+        let mut queue = self.lobbies.get_mut(room_id)?;
+        let index = queue.iter().position(|k| k.user_id == user_id)?;
+        Some(queue.remove(index))
+    }
+
+    pub fn take_lobby(&self, room_id: &str) -> Vec<Knock> {
+        self.lobbies.remove(room_id).map(|(_, q)| q).unwrap_or_default()
+    }
+
+    /// Drops the socket's knocks, returning the rooms it was waiting for.
+    pub fn withdraw_knocks(&self, socket_id: &str) -> Vec<String> {
+        let mut rooms = Vec::new();
+        for mut queue in self.lobbies.iter_mut() {
+            let before = queue.len();
+            queue.retain(|k| k.socket_id != socket_id);
+            if queue.len() != before {
+                rooms.push(queue.key().clone());
+            }
+        }
+        self.lobbies.retain(|_, q| !q.is_empty());
+        rooms
+    }
+
+    pub fn admit(&self, room_id: &str, user_id: &str, ttl: Duration) {
+        self.admissions.retain(|_, until| *until > Instant::now());
+        self.admissions.insert((room_id.to_string(), user_id.to_string()), Instant::now() + ttl);
+    }
+
+    pub fn take_admission(&self, room_id: &str, user_id: &str) -> bool {
+        self.admissions.remove(&(room_id.to_string(), user_id.to_string()))
+            .is_some_and(|(_, until)| until > Instant::now())
+    }
+
+    /// Refuses an object whose id is already taken, in this room or another.
+    /// The objects table's primary key decides between two adds racing for
+    /// the same id, so the object is stored before the room shows it.
+    pub async fn add_object(&self, room_id: &str, object: crate::types::RoomObject) -> Result<(), &'static str> {
+        if !self.rooms.contains_key(room_id) {
+            return Err("Room not found");
+        }
+        match self.db.insert_object(room_id, &object).await {
+            Ok(()) => {}
+            Err(error) if db::is_unique_violation(&error) => return Err("Object id already in use"),
+            Err(error) => {
+                warn!("Failed to save object {} in room {}: {}", object.id, room_id, error);
+                return Err("Failed to save object");
+            }
+        }
+        match self.rooms.get_mut(room_id) {
+            Some(mut room) => {
+                room.objects.push(object);
+                Ok(())
+            }
+            None => {
+                // Deleted while we were saving
+                let _ = self.db.delete_object(room_id, &object.id).await;
+                Err("Room not found")
+            }
         }
     }
 
@@ -143,19 +461,88 @@ impl AppState {
 
     pub fn update_room_background(&self, room_id: String, background: Option<String>) {
         if let Some(mut room) = self.rooms.get_mut(&room_id) {
-            room.background = background;
+            room.background = background.clone();
+            let db = self.db.clone();
+            tokio::spawn(async move {
+                let _ = db.save_room_setting(&room_id, "background", background.as_deref()).await;
+            });
         }
     }
 
+    pub fn update_room_capacity(&self, room_id: String, max_users: Option<usize>) {
+        if let Some(mut room) = self.rooms.get_mut(&room_id) {
+            room.max_users = max_users;
+            let db = self.db.clone();
+            tokio::spawn(async move {
+                let value = max_users.map(|m| m.to_string());
+                let _ = db.save_room_setting(&room_id, "max_users", value.as_deref()).await;
+            });
+        }
+    }
+
+    pub fn update_room_knock(&self, room_id: String, knock: bool) {
+        if let Some(mut room) = self.rooms.get_mut(&room_id) {
+            room.knock = knock;
+            let db = self.db.clone();
+            tokio::spawn(async move {
+                let _ = db.save_room_setting(&room_id, "knock", knock.then_some("true")).await;
+            });
+        }
+    }
+
+    /// Puts back the objects and settings of `restored`, keeping who is in the room.
+    pub fn restore_room(&self, restored: &Room) -> Option<Room> {
+        let mut room = self.rooms.get_mut(&restored.id)?;
+        room.objects = restored.objects.clone();
+        room.background = restored.background.clone();
+        room.max_users = restored.max_users;
+        room.knock = restored.knock;
+        Some(room.clone())
+    }
+
     pub fn remove_object(&self, room_id: String, object_id: String) {
         if let Some(mut room) = self.rooms.get_mut(&room_id) {
             if let Some(pos) = room.objects.iter().position(|o| o.id == object_id) {
                 room.objects.remove(pos);
                 let db = self.db.clone();
                 tokio::spawn(async move {
-                    let _ = db.delete_object(&object_id).await;
+                    let _ = db.delete_object(&room_id, &object_id).await;
                 });
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::RoomObject;
+    use serde_json::json;
+
+    fn add_room(state: &AppState, id: &str) {
+        let room: Room = serde_json::from_value(json!({ "id": id, "name": id, "users": [] })).unwrap();
+        state.rooms.insert(id.to_string(), room);
+    }
+
+    fn note(id: &str) -> RoomObject {
+        serde_json::from_value(json!({
+            "id": id, "type": "note", "x": 0.0, "y": 0.0, "width": 1.0, "height": 1.0, "content": "hi",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn racing_adds_of_one_object_id_keep_a_single_copy() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        add_room(&state, "a");
+        add_room(&state, "b");
+
+        let (in_a, in_b) = tokio::join!(state.add_object("a", note("shared")), state.add_object("b", note("shared")));
+        assert!(in_a.is_ok() != in_b.is_ok());
+        assert_eq!(in_a.err().or(in_b.err()), Some("Object id already in use"));
+        let copies: usize = state.rooms.iter().map(|room| room.objects.len()).sum();
+        assert_eq!(copies, 1);
+
+        assert_eq!(state.add_object("gone", note("other")).await, Err("Room not found"));
+    }
+}
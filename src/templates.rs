@@ -0,0 +1,151 @@
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    Extension, Json,
+};
+use serde::Deserialize;
+use socketioxide::SocketIo;
+use crate::api::{self, CreateRoom};
+use crate::auth::AuthUser;
+use crate::roles::Permission;
+use crate::state::AppState;
+use crate::types::{Room, RoomObject, RoomTemplate};
+
+#[derive(Deserialize)]
+pub struct SaveTemplate {
+    #[serde(rename = "roomId")]
+    room_id: String,
+    name: String,
+}
+
+/// The room a template is taken from, or a clone made of: the caller needs
+/// to be able to change its settings.
+fn source_room(state: &AppState, room_id: &str, user: &AuthUser) -> Result<Room, (StatusCode, &'static str)> {
+    let Some(room) = state.get_room(room_id) else {
+        return Err((StatusCode::NOT_FOUND, "Room not found"));
+    };
+    if !room.role_of(Some(&user.account_id)).can(Permission::ChangeSettings) {
+        return Err((StatusCode::FORBIDDEN, "Not allowed to copy this room"));
+    }
+    Ok(room)
+}
+
+// Objects are looked up by id alone, so copies need ids of their own
+pub fn copy_objects(objects: &[RoomObject]) -> Vec<RoomObject> {
+    objects.iter()
+        .map(|o| RoomObject { id: uuid::Uuid::new_v4().to_string(), ..o.clone() })
+        .collect()
+}
+
+async fn owned_template(state: &AppState, template_id: &str, user: &AuthUser) -> Result<RoomTemplate, (StatusCode, &'static str)> {
+    match state.db.get_template(template_id).await {
+        Ok(Some(template)) if template.owner_id == user.account_id => Ok(template),
+        Ok(_) => Err((StatusCode::NOT_FOUND, "Template not found")),
+        Err(_) => Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to load template")),
+    }
+}
+
+pub async fn list(State(state): State<AppState>, user: AuthUser) -> impl IntoResponse {
+    match state.db.get_templates(&user.account_id).await {
+        Ok(templates) => Json(templates).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load templates").into_response(),
+    }
+}
+
+/// Saves a room's objects and settings as a template of the caller's.
+pub async fn save(
+    State(state): State<AppState>,
+    user: AuthUser,
+    Json(payload): Json<SaveTemplate>,
+) -> impl IntoResponse {
+    let room = match source_room(&state, &payload.room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    let name = payload.name.trim();
+    if name.is_empty() || name.chars().count() > 64 {
+        return (StatusCode::BAD_REQUEST, "Template name must be 1 to 64 characters").into_response();
+    }
+
+    let template = RoomTemplate {
+        id: uuid::Uuid::new_v4().to_string(),
+        name: name.to_string(),
+        owner_id: user.account_id,
+        background: room.background,
+        max_users: room.max_users,
+        knock: room.knock,
+        objects: room.objects,
+        created_at: chrono::Utc::now().timestamp(),
+    };
+    match state.db.save_template(&template).await {
+        Ok(_) => (StatusCode::CREATED, Json(template)).into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to save template").into_response(),
+    }
+}
+
+pub async fn get(State(state): State<AppState>, user: AuthUser, Path(template_id): Path<String>) -> impl IntoResponse {
+    match owned_template(&state, &template_id, &user).await {
+        Ok(template) => Json(template).into_response(),
+        Err(error) => error.into_response(),
+    }
+}
+
+pub async fn delete(State(state): State<AppState>, user: AuthUser, Path(template_id): Path<String>) -> impl IntoResponse {
+    if let Err(error) = owned_template(&state, &template_id, &user).await {
+        return error.into_response();
+    }
+    match state.db.delete_template(&template_id).await {
+        Ok(_) => (StatusCode::OK, "Template deleted").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to delete template").into_response(),
+    }
+}
+
+/// Creates a room from a template. Takes the same body as `POST /api/rooms`.
+pub async fn create_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(template_id): Path<String>,
+    Json(payload): Json<CreateRoom>,
+) -> impl IntoResponse {
+    let template = match owned_template(&state, &template_id, &user).await {
+        Ok(template) => template,
+        Err(error) => return error.into_response(),
+    };
+    let mut room = match api::new_room(&state, &user.account_id, payload).await {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+
+    room.objects = copy_objects(&template.objects);
+    room.background = template.background;
+    room.max_users = template.max_users;
+    room.knock = template.knock;
+    api::insert_room(&state, &io, room).await
+}
+
+/// Copies a room's objects and settings into a new room of the caller's.
+/// People, roles, bans and chat stay behind.
+pub async fn clone_room(
+    State(state): State<AppState>,
+    Extension(io): Extension<SocketIo>,
+    user: AuthUser,
+    Path(room_id): Path<String>,
+    Json(payload): Json<CreateRoom>,
+) -> impl IntoResponse {
+    let source = match source_room(&state, &room_id, &user) {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+    let mut room = match api::new_room(&state, &user.account_id, payload).await {
+        Ok(room) => room,
+        Err(error) => return error.into_response(),
+    };
+
+    room.objects = copy_objects(&source.objects);
+    room.background = source.background;
+    room.max_users = source.max_users;
+    room.knock = source.knock;
+    api::insert_room(&state, &io, room).await
+}
@@ -0,0 +1,109 @@
+use dashmap::DashMap;
+use std::time::{Duration, Instant};
+
+// Failures older than this are forgotten
+const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+const BASE_LOCKOUT: Duration = Duration::from_secs(30);
+const MAX_LOCKOUT: Duration = Duration::from_secs(60 * 60);
+
+struct Attempts {
+    failures: u32,
+    last_failure: Instant,
+    locked_until: Option<Instant>,
+}
+
+/// Counts failed logins per key (an account name or a client IP). Once a key
+/// reaches `threshold` failures it is locked out, and every further failure
+/// doubles the lockout, up to an hour.
+pub struct LoginThrottle {
+    threshold: u32,
+    attempts: DashMap<String, Attempts>,
+}
+
+impl LoginThrottle {
+    pub fn new(threshold: u32) -> Self {
+        Self {
+            threshold,
+            attempts: DashMap::new(),
+        }
+    }
+
+    /// Returns how long the key is still locked out for, if it is.
+    pub fn check(&self, key: &str) -> Option<Duration> {
+        let now = Instant::now();
+        self.attempts.get(key)
+            .and_then(|a| a.locked_until)
+            .filter(|until| *until > now)
+            .map(|until| until - now)
+    }
+
+    pub fn record_failure(&self, key: &str) {
+        let now = Instant::now();
+        if self.attempts.len() > 10_000 {
+            self.attempts.retain(|_, a| now.duration_since(a.last_failure) < FAILURE_WINDOW);
+        }
+
+        let mut entry = self.attempts.entry(key.to_string()).or_insert(Attempts {
+            failures: 0,
+            last_failure: now,
+            locked_until: None,
+        });
+        if now.duration_since(entry.last_failure) >= FAILURE_WINDOW {
+            entry.failures = 0;
+        }
+        entry.failures += 1;
+        entry.last_failure = now;
+
+        if entry.failures >= self.threshold {
+            let doublings = (entry.failures - self.threshold).min(16);
+            let lockout = BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT);
+            entry.locked_until = Some(now + lockout);
+        }
+    }
+
+    pub fn reset(&self, key: &str) {
+        self.attempts.remove(key);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn locks_out_at_the_threshold_and_doubles_from_there() {
+        let throttle = LoginThrottle::new(3);
+        throttle.record_failure("alice");
+        throttle.record_failure("alice");
+        assert_eq!(throttle.check("alice"), None);
+
+        throttle.record_failure("alice");
+        let first = throttle.check("alice").unwrap();
+        assert!(first <= BASE_LOCKOUT && first > BASE_LOCKOUT - Duration::from_secs(5));
+
+        throttle.record_failure("alice");
+        let second = throttle.check("alice").unwrap();
+        assert!(second > BASE_LOCKOUT && second <= BASE_LOCKOUT * 2);
+
+        // Other keys are counted separately
+        assert_eq!(throttle.check("bob"), None);
+    }
+
+    #[test]
+    fn lockouts_are_capped() {
+        let throttle = LoginThrottle::new(1);
+        for _ in 0..40 {
+            throttle.record_failure("alice");
+        }
+        assert!(throttle.check("alice").unwrap() <= MAX_LOCKOUT);
+    }
+
+    #[test]
+    fn reset_clears_the_count() {
+        let throttle = LoginThrottle::new(2);
+        throttle.record_failure("alice");
+        throttle.reset("alice");
+        throttle.record_failure("alice");
+        assert_eq!(throttle.check("alice"), None);
+    }
+}
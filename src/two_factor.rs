@@ -0,0 +1,242 @@
+use axum::{
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, StatusCode},
+    response::IntoResponse,
+    Json,
+};
+use serde::Deserialize;
+use serde_json::json;
+use std::net::SocketAddr;
+use totp_rs::{Algorithm, Secret, TOTP};
+use crate::account;
+use crate::auth::{self, AuthUser};
+use crate::state::AppState;
+
+const ISSUER: &str = "VoiceSpaces";
+const STEP: u64 = 30;
+const RECOVERY_CODE_COUNT: usize = 10;
+
+#[derive(Deserialize)]
+pub struct CodePayload {
+    code: String,
+}
+
+#[derive(Deserialize)]
+pub struct SetupPayload {
+    #[serde(default)] // accounts made through single sign-on may have none yet
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DisablePayload {
+    password: String,
+    code: String,
+}
+
+fn totp(secret: &str, username: &str) -> Option<TOTP> {
+    let bytes = Secret::Encoded(secret.to_string()).to_bytes().ok()?;
+    TOTP::new(Algorithm::SHA1, 6, 1, STEP, bytes, Some(ISSUER.to_string()), username.to_string()).ok()
+}
+
+/// The time step `code` was generated for, allowing one step of clock drift either way.
+/// Every step is compared, in constant time, whichever one matches.
+fn matching_step(totp: &TOTP, code: &str, now: u64) -> Option<i64> {
+    let now = now / STEP;
+    (now.saturating_sub(1)..=now + 1)
+        .fold(None, |found, step| {
+            let matches = auth::constant_time_eq(totp.generate(step * STEP).as_bytes(), code.as_bytes());
+            if matches { Some(step as i64) } else { found }
+        })
+}
+
+fn current_time() -> u64 {
+    chrono::Utc::now().timestamp() as u64
+}
+
+// Codes are typed by hand, so ignore spacing, dashes and case
+fn normalize(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+fn new_recovery_code() -> String {
+    let hex: String = rand::random::<[u8; 5]>()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    format!("{}-{}", &hex[..5], &hex[5..])
+}
+
+/// Checks a TOTP code or an unused recovery code for an account with 2FA
+/// enabled. Each code is accepted at most once.
+pub async fn verify_code(state: &AppState, account_id: &str, username: &str, code: &str) -> Result<bool, sqlx::Error> {
+    let Some((Some(secret), _, _)) = state.db.get_totp(account_id).await? else {
+        return Ok(false);
+    };
+    let code = normalize(code);
+
+    if code.len() == 6 && code.chars().all(|c| c.is_ascii_digit()) {
+        return match totp(&secret, username).and_then(|t| matching_step(&t, &code, current_time())) {
+            Some(step) => state.db.consume_totp_step(account_id, step).await,
+            None => Ok(false),
+        };
+    }
+
+    // Look the code up among the account's own, compared in constant time,
+    // rather than letting the database index search for it
+    let code_hash = auth::hash_token(&code);
+    let matched = state.db.get_unused_recovery_hashes(account_id).await?
+        .into_iter()
+        .fold(None, |found, stored| {
+            if auth::constant_time_eq(stored.as_bytes(), code_hash.as_bytes()) { Some(stored) } else { found }
+        });
+    match matched {
+        Some(stored) => state.db.consume_recovery_code(account_id, &stored).await,
+        None => Ok(false),
+    }
+}
+
+pub async fn is_enabled(state: &AppState, account_id: &str) -> Result<bool, sqlx::Error> {
+    Ok(matches!(state.db.get_totp(account_id).await?, Some((Some(_), _, _))))
+}
+
+/// Starts enrollment: generates a secret that only takes effect once a code
+/// from it is confirmed. Needs the password, as turning 2FA off does.
+pub async fn setup(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: AuthUser,
+    Json(payload): Json<SetupPayload>,
+) -> impl IntoResponse {
+    let ip = auth::client_ip(&headers, peer);
+    if let Err(response) = account::reauthenticate(&state, &user, &ip, &payload.password).await {
+        return response;
+    }
+    match is_enabled(&state, &user.account_id).await {
+        Ok(false) => {}
+        Ok(true) => return (StatusCode::CONFLICT, "Two-factor authentication is already enabled").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to start enrollment").into_response(),
+    }
+
+    let secret = Secret::Raw(rand::random::<[u8; 20]>().to_vec()).to_encoded().to_string();
+    let Some(totp) = totp(&secret, &user.username) else {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to start enrollment").into_response();
+    };
+
+    if state.db.set_pending_totp(&user.account_id, &secret).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to start enrollment").into_response();
+    }
+
+    Json(json!({
+        "secret": secret,
+        "otpauthUri": totp.get_url(),
+    })).into_response()
+}
+
+/// Finishes enrollment and returns the recovery codes. They are only stored
+/// hashed, so this is the one time they can be shown.
+pub async fn confirm(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: AuthUser,
+    Json(payload): Json<CodePayload>,
+) -> impl IntoResponse {
+    let account_key = user.username.to_lowercase();
+    let ip = auth::client_ip(&headers, peer);
+    if let Some(retry_after) = state.account_throttle.check(&account_key)
+        .into_iter()
+        .chain(state.ip_throttle.check(&ip))
+        .max()
+    {
+        return auth::locked_out(retry_after);
+    }
+
+    let pending = match state.db.get_totp(&user.account_id).await {
+        Ok(Some((_, Some(pending), _))) => pending,
+        Ok(_) => return (StatusCode::BAD_REQUEST, "No enrollment in progress").into_response(),
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to confirm enrollment").into_response(),
+    };
+
+    let step = totp(&pending, &user.username).and_then(|t| matching_step(&t, &normalize(&payload.code), current_time()));
+    let Some(step) = step else {
+        state.account_throttle.record_failure(&account_key);
+        state.ip_throttle.record_failure(&ip);
+        return (StatusCode::UNAUTHORIZED, "Invalid code").into_response();
+    };
+
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT).map(|_| new_recovery_code()).collect();
+    let hashes: Vec<String> = codes.iter().map(|c| auth::hash_token(&normalize(c))).collect();
+    if state.db.enable_totp(&user.account_id, step, &hashes).await.is_err() {
+        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to confirm enrollment").into_response();
+    }
+
+    Json(json!({ "recoveryCodes": codes })).into_response()
+}
+
+pub async fn disable(
+    State(state): State<AppState>,
+    ConnectInfo(peer): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    user: AuthUser,
+    Json(payload): Json<DisablePayload>,
+) -> impl IntoResponse {
+    let ip = auth::client_ip(&headers, peer);
+    if let Err(response) = account::check_password(&state, &user, &ip, &payload.password).await {
+        return response;
+    }
+    match verify_code(&state, &user.account_id, &user.username, &payload.code).await {
+        Ok(true) => {}
+        Ok(false) => {
+            state.account_throttle.record_failure(&user.username.to_lowercase());
+            state.ip_throttle.record_failure(&ip);
+            return (StatusCode::UNAUTHORIZED, "Invalid code").into_response();
+        }
+        Err(_) => return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to verify code").into_response(),
+    }
+
+    match state.db.disable_totp(&user.account_id).await {
+        Ok(_) => (StatusCode::OK, "Two-factor authentication disabled").into_response(),
+        Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, "Failed to disable two-factor authentication").into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::KeyRing;
+
+    const SECRET: &str = "JBSWY3DPEHPK3PXPJBSWY3DPEHPK3PXP";
+
+    #[test]
+    fn codes_match_within_one_step_of_drift() {
+        let totp = totp(SECRET, "carol").unwrap();
+        let now = 1_700_000_000;
+        let step = (now / STEP) as i64;
+
+        assert_eq!(matching_step(&totp, &totp.generate(now), now), Some(step));
+        assert_eq!(matching_step(&totp, &totp.generate(now - STEP), now), Some(step - 1));
+        assert_eq!(matching_step(&totp, &totp.generate(now + STEP), now), Some(step + 1));
+        assert_eq!(matching_step(&totp, &totp.generate(now - 2 * STEP), now), None);
+        assert_eq!(matching_step(&totp, "12345", now), None);
+    }
+
+    #[tokio::test]
+    async fn recovery_codes_work_once() {
+        let state = AppState::new("sqlite::memory:", KeyRing::from_env().unwrap(), None).await.unwrap();
+        state.db.create_account("acc-1", "carol", "hash").await.unwrap();
+        state.db.set_pending_totp("acc-1", SECRET).await.unwrap();
+        let codes = ["abcde-12345", "fedcb-54321"];
+        let hashes: Vec<String> = codes.iter().map(|c| auth::hash_token(&normalize(c))).collect();
+        state.db.enable_totp("acc-1", 0, &hashes).await.unwrap();
+
+        // Typed with different case and spacing
+        assert!(verify_code(&state, "acc-1", "carol", "ABCDE 12345").await.unwrap());
+        assert!(!verify_code(&state, "acc-1", "carol", "abcde-12345").await.unwrap());
+        assert!(!verify_code(&state, "acc-1", "carol", "00000-00000").await.unwrap());
+        assert!(verify_code(&state, "acc-1", "carol", "fedcb-54321").await.unwrap());
+    }
+}
@@ -1,4 +1,6 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use crate::roles::{Permission, Role};
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct User {
@@ -9,9 +11,51 @@ pub struct User {
     pub y: f64,
     #[serde(rename = "roomId")]
     pub room_id: String,
+    #[serde(default, rename = "accountId", skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>, // None for guests
+    #[serde(default)]
+    pub reconnecting: bool, // dropped out, within the reconnect grace period
+    #[serde(default, rename = "chatMuted")]
+    pub chat_muted: bool,
+    #[serde(default, rename = "voiceMuted")]
+    pub voice_muted: bool,
+}
+
+/// What the server knows about a connected socket. `user_id` is the stable
+/// identity shared by all of a user's sockets: the account id for logged-in
+/// users, `guest-<uuid>` for guests.
+#[derive(Debug, Clone)]
+pub struct SocketSession {
+    pub user_id: String,
+    pub room_id: Option<String>,
+    // Handed to the client so it can recover this session after a drop
+    pub recovery_id: String,
 }
 
+/// A socket that dropped out of a room without leaving it. Its user stays in
+/// the room until the grace period ends, and room events sent meanwhile are
+/// kept to replay if the client comes back with the same recovery id.
+#[derive(Debug, Clone)]
+pub struct ParkedSession {
+    pub user_id: String,
+    pub room_id: String,
+    pub expires_at: std::time::Instant,
+    pub events: Vec<(String, serde_json::Value)>,
+    // Too much happened to replay; the client has to rejoin instead
+    pub overflowed: bool,
+}
+
+/// Account a socket authenticated as during the handshake.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Identity {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    pub username: String,
+    #[serde(skip)]
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct RoomObject {
     pub id: String,
     #[serde(rename = "type")]
@@ -36,6 +80,78 @@ pub struct Room {
     pub objects: Vec<RoomObject>,
     #[serde(default)]
     pub background: Option<String>,
+    #[serde(default, rename = "ownerId")]
+    pub owner_id: Option<String>, // None for rooms created implicitly by joining
+    #[serde(default)]
+    pub visibility: Visibility,
+    #[serde(skip)]
+    pub password_hash: Option<String>, // bcrypt
+    #[serde(default)]
+    pub roles: HashMap<String, Role>, // account id -> role, for everyone but the owner
+    #[serde(default, rename = "maxUsers")]
+    pub max_users: Option<usize>,
+    #[serde(default)]
+    pub knock: bool, // joiners wait in the lobby until a moderator lets them in
+    #[serde(default)]
+    pub description: Option<String>,
+    #[serde(default)]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+impl Room {
+    /// Rooms without an owner keep the old free-for-all: everyone in them has
+    /// moderator permissions over the room's content. See `allows` for what
+    /// that leaves out.
+    pub fn role_of(&self, account_id: Option<&str>) -> Role {
+        let Some(owner_id) = &self.owner_id else {
+            return Role::Moderator;
+        };
+        match account_id {
+            Some(id) if id == owner_id => Role::Owner,
+            Some(id) => self.roles.get(id).copied().unwrap_or(Role::Member),
+            None => Role::Guest,
+        }
+    }
+
+    /// Whether `account_id` may use `permission` here. In rooms without an
+    /// owner nobody outranks anyone else, so nobody can moderate other people
+    /// or hand out roles there, even though everyone can change the content.
+    pub fn allows(&self, account_id: Option<&str>, permission: Permission) -> bool {
+        let over_people = matches!(permission, Permission::Moderate | Permission::ManageRoles);
+        (self.owner_id.is_some() || !over_people) && self.role_of(account_id).can(permission)
+    }
+}
+
+/// Who can see and join a room. Only public rooms are listed; private ones
+/// also need an invite (or being the owner) to join.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    Unlisted,
+    Private,
+}
+
+impl Visibility {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Visibility::Public => "public",
+            Visibility::Unlisted => "unlisted",
+            Visibility::Private => "private",
+        }
+    }
+
+    pub fn parse(value: &str) -> Option<Self> {
+        match value {
+            "public" => Some(Visibility::Public),
+            "unlisted" => Some(Visibility::Unlisted),
+            "private" => Some(Visibility::Private),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -47,6 +163,84 @@ pub struct ChatMessage {
     pub user_name: String,
     pub text: String,
     pub timestamp: i64,
+    #[serde(default, rename = "accountId", skip_serializing_if = "Option::is_none")]
+    pub account_id: Option<String>,
+    #[serde(default, rename = "editedAt", skip_serializing_if = "Option::is_none")]
+    pub edited_at: Option<i64>,
+    // Deleted messages keep their place in the history, without their text
+    #[serde(default, rename = "deletedAt", skip_serializing_if = "Option::is_none")]
+    pub deleted_at: Option<i64>,
+    // The message a reply belongs to. Threads are one level deep.
+    #[serde(default, rename = "parentId", skip_serializing_if = "Option::is_none")]
+    pub parent_id: Option<String>,
+    #[serde(default, rename = "replyCount")]
+    pub reply_count: i64,
+    #[serde(default)]
+    pub reactions: Vec<Reaction>,
+}
+
+/// Everyone who reacted to a message with the same emoji.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Reaction {
+    pub emoji: String,
+    pub count: usize,
+    #[serde(rename = "userIds")]
+    pub user_ids: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AccountProfile {
+    #[serde(rename = "accountId")]
+    pub account_id: String,
+    pub username: String,
+    #[serde(rename = "displayName")]
+    pub display_name: Option<String>,
+    #[serde(rename = "avatarColor")]
+    pub avatar_color: Option<String>,
+    pub bio: Option<String>,
+}
+
+/// Someone waiting in a room's lobby.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Knock {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    pub name: String,
+    #[serde(skip)]
+    pub socket_id: String,
+    pub since: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomBan {
+    #[serde(rename = "userId")]
+    pub user_id: String,
+    #[serde(rename = "bannedBy")]
+    pub banned_by: String,
+    pub reason: Option<String>,
+    #[serde(rename = "expiresAt")]
+    pub expires_at: Option<i64>, // None bans for good
+    #[serde(rename = "createdAt")]
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Report {
+    pub id: String,
+    #[serde(rename = "roomId")]
+    pub room_id: String,
+    #[serde(rename = "reporterId")]
+    pub reporter_id: String,
+    #[serde(rename = "targetId")]
+    pub target_id: String,
+    pub reason: String,
+    pub status: String, // "open" or "resolved"
+    #[serde(rename = "createdAt")]
+    pub created_at: i64,
+    #[serde(rename = "resolvedBy")]
+    pub resolved_by: Option<String>,
+    #[serde(rename = "resolvedAt")]
+    pub resolved_at: Option<i64>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -74,3 +268,107 @@ pub struct ReturnSignalPayload {
     pub signal: serde_json::Value,
     pub id: String,
 }
+
+/// A room's layout and settings saved for reuse, without its people.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomTemplate {
+    pub id: String,
+    pub name: String,
+    pub owner_id: String,
+    pub background: Option<String>,
+    pub max_users: Option<usize>,
+    pub knock: bool,
+    pub objects: Vec<RoomObject>,
+    pub created_at: i64,
+}
+
+/// A room's objects and settings at one point in time. Automatic snapshots
+/// have no `created_by`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomSnapshot {
+    pub id: String,
+    pub room_id: String,
+    pub label: Option<String>,
+    pub created_by: Option<String>,
+    pub background: Option<String>,
+    pub max_users: Option<usize>,
+    pub knock: bool,
+    pub objects: Vec<RoomObject>,
+    pub created_at: i64,
+}
+
+/// A direct message conversation as seen by one of its members. One-to-one
+/// conversations have no name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DmConversation {
+    pub id: String,
+    pub name: Option<String>,
+    pub members: Vec<DmMember>,
+    pub created_at: i64,
+    pub last_message_at: Option<i64>,
+    pub unread_count: i64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DmMember {
+    pub account_id: String,
+    pub username: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DirectMessage {
+    pub id: String,
+    pub conversation_id: String,
+    pub sender_id: String,
+    pub sender_name: String,
+    pub text: String,
+    pub timestamp: i64,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn room(owner_id: Option<&str>) -> Room {
+        serde_json::from_value(json!({
+            "id": "room-1",
+            "name": "Room 1",
+            "users": [],
+            "ownerId": owner_id,
+            "roles": { "mod": "moderator" },
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn roles_come_from_the_owner_and_the_stored_ones() {
+        let room = room(Some("owner"));
+        assert_eq!(room.role_of(Some("owner")), Role::Owner);
+        assert_eq!(room.role_of(Some("mod")), Role::Moderator);
+        assert_eq!(room.role_of(Some("someone")), Role::Member);
+        assert_eq!(room.role_of(None), Role::Guest);
+    }
+
+    #[test]
+    fn unowned_rooms_share_the_content_but_not_moderation() {
+        let unowned = room(None);
+        for account_id in [Some("someone"), None] {
+            assert!(unowned.allows(account_id, Permission::ChangeSettings));
+            assert!(unowned.allows(account_id, Permission::RemoveObjects));
+            assert!(!unowned.allows(account_id, Permission::Moderate));
+            assert!(!unowned.allows(account_id, Permission::ManageRoles));
+        }
+
+        let owned = room(Some("owner"));
+        assert!(owned.allows(Some("owner"), Permission::ManageRoles));
+        assert!(owned.allows(Some("mod"), Permission::Moderate));
+        assert!(!owned.allows(Some("someone"), Permission::Moderate));
+        assert!(!owned.allows(Some("someone"), Permission::ChangeSettings));
+    }
+}